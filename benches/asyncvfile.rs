@@ -0,0 +1,140 @@
+//! Measure AsyncVFile throughput with concurrent downloads, and the latency of API calls
+//! made on the blocking pool while slow clients are downloading.
+//! Each download read from a file that simulate disk latency, run with `cargo bench --bench asyncvfile`.
+
+use std::thread;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
+
+use rocket::tokio;
+use rocket::tokio::io::AsyncReadExt;
+
+use tapir::asyncvfile::AsyncVFile;
+
+/// Size of each downloaded file.
+const FILE_SIZE : u64 = 256 * 1024 * 1024;
+/// Worker threads of the runtime, as a small Rocket instance.
+const WORKERS : usize = 4;
+/// Blocking threads of the runtime, kept small so downloads holding threads would starve the API calls.
+const BLOCKING_THREADS : usize = 16;
+/// Size of the file downloaded by the slow clients.
+const SLOW_FILE_SIZE : u64 = 64 * 1024 * 1024;
+/// Number of API calls made while the slow clients are downloading.
+const API_CALLS : usize = 200;
+
+/// File that sleep on each read to simulate disk latency.
+struct SlowFile
+{
+  inner : io::Take<io::Repeat>,
+  latency : Duration,
+}
+
+impl Read for SlowFile
+{
+  fn read(&mut self, buf : &mut [u8]) -> io::Result<usize>
+  {
+    thread::sleep(self.latency);
+    self.inner.read(buf)
+  }
+}
+
+/// Download a file of `size` bytes, waiting `client_delay` after each read to simulate a slow client.
+async fn download_slowly(size : u64, latency : Duration, client_delay : Duration) -> u64
+{
+  let file = SlowFile{ inner : io::repeat(0).take(size), latency };
+  let mut stream = AsyncVFile::new(Box::new(file), None);
+  let mut buffer = vec![0u8; 64 * 1024];
+  let mut total = 0;
+
+  loop
+  {
+    match stream.read(&mut buffer).await.unwrap()
+    {
+      0 => return total,
+      readed => total += readed as u64,
+    }
+    tokio::time::sleep(client_delay).await;
+  }
+}
+
+/// Run a short job on the blocking pool like a request handler and return how long the request took.
+async fn api_call() -> Duration
+{
+  let start = Instant::now();
+  tokio::task::spawn_blocking(|| thread::sleep(Duration::from_millis(1))).await.unwrap();
+  start.elapsed()
+}
+
+async fn download(latency : Duration) -> u64
+{
+  let file = SlowFile{ inner : io::repeat(0).take(FILE_SIZE), latency };
+  let mut stream = AsyncVFile::new(Box::new(file), None);
+  let mut buffer = vec![0u8; 64 * 1024];
+  let mut total = 0;
+
+  loop
+  {
+    match stream.read(&mut buffer).await.unwrap()
+    {
+      0 => return total,
+      readed => total += readed as u64,
+    }
+  }
+}
+
+fn main()
+{
+  let runtime = tokio::runtime::Builder::new_multi_thread()
+                  .worker_threads(WORKERS)
+                  .max_blocking_threads(BLOCKING_THREADS)
+                  .enable_all()
+                  .build()
+                  .unwrap();
+
+  for latency in [Duration::ZERO, Duration::from_micros(200)]
+  {
+    for concurrency in [1, 4, 16, 64]
+    {
+      let start = Instant::now();
+      let total : u64 = runtime.block_on(async
+      {
+        let downloads : Vec<_> = (0..concurrency).map(|_| tokio::spawn(download(latency))).collect();
+        let mut total = 0;
+        for download in downloads
+        {
+          total += download.await.unwrap();
+        }
+        total
+      });
+      let elapsed = start.elapsed();
+
+      println!("latency {:>5?} concurrency {:>3} : {:>8.1} MiB/s", latency, concurrency,
+               total as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64());
+    }
+  }
+
+  //more slow clients than blocking threads, API calls must still be answered quickly
+  for slow_clients in [0, BLOCKING_THREADS, BLOCKING_THREADS * 4]
+  {
+    let (latencies, elapsed) = runtime.block_on(async
+    {
+      let start = Instant::now();
+      let downloads : Vec<_> = (0..slow_clients).map(|_| tokio::spawn(download_slowly(SLOW_FILE_SIZE, Duration::ZERO, Duration::from_millis(2)))).collect();
+
+      let mut latencies = Vec::with_capacity(API_CALLS);
+      for _ in 0..API_CALLS
+      {
+        latencies.push(api_call().await);
+      }
+      for download in downloads
+      {
+        download.abort();
+      }
+      (latencies, start.elapsed())
+    });
+
+    let max = latencies.iter().max().copied().unwrap_or_default();
+    let average = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    println!("slow clients {:>3} : {} API calls in {:>8.1?}, average {:>8.1?}, max {:>8.1?}", slow_clients, API_CALLS, elapsed, average, max);
+  }
+}
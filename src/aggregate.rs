@@ -0,0 +1,226 @@
+//! Count of the nodes returned by a query grouped by the value of an attribute.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike, Utc};
+
+use tap::session::Session;
+use tap::tree::TreeNodeId;
+
+use crate::error::ApiError;
+use crate::nodevalue::node_value;
+
+/// Number of buckets returned by a terms aggregation if no size is given.
+const TERMS_SIZE : usize = 10;
+/// Maximum number of buckets of a histogram, a smaller interval must be used if there is more.
+const MAX_BUCKETS : usize = 10_000;
+
+/// Period covered by a bucket of a date histogram.
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DateInterval
+{
+  Minute,
+  Hour,
+  Day,
+  Week,
+  Month,
+  Year,
+}
+
+impl DateInterval
+{
+  /// Return the start of the period containing `date`.
+  fn truncate(&self, date : DateTime<Utc>) -> DateTime<Utc>
+  {
+    let naive = date.naive_utc();
+    let day = naive.date();
+    let start = match self
+    {
+      DateInterval::Minute => day.and_hms_opt(naive.time().hour(), naive.time().minute(), 0),
+      DateInterval::Hour => day.and_hms_opt(naive.time().hour(), 0, 0),
+      DateInterval::Day => day.and_hms_opt(0, 0, 0),
+      DateInterval::Week => (day - Duration::days(day.weekday().num_days_from_monday() as i64)).and_hms_opt(0, 0, 0),
+      DateInterval::Month => NaiveDate::from_ymd_opt(day.year(), day.month(), 1).and_then(|day| day.and_hms_opt(0, 0, 0)),
+      DateInterval::Year => NaiveDate::from_ymd_opt(day.year(), 1, 1).and_then(|day| day.and_hms_opt(0, 0, 0)),
+    };
+    start.map_or(date, |start| Utc.from_utc_datetime(&start))
+  }
+}
+
+/// How the values are grouped.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Bucket
+{
+  /// One bucket for each distinct value, the `size` most frequent values are returned.
+  Terms { size : Option<usize> },
+  /// Numeric values grouped by ranges of `interval`.
+  Histogram { interval : f64 },
+  /// Dates grouped by period.
+  DateHistogram { interval : DateInterval },
+}
+
+/// Number of nodes having a value, or a value in the range starting at `key`.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct BucketCount
+{
+  pub key : Value,
+  pub count : usize,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Aggregation
+{
+  /// Number of nodes returned by the query.
+  pub total : usize,
+  /// Nodes without the attribute, or with a value that can't be put in a bucket.
+  pub missing : usize,
+  /// Nodes with a value that isn't part of the returned terms.
+  pub other : usize,
+  pub buckets : Vec<BucketCount>,
+}
+
+fn too_many_buckets() -> ApiError
+{
+  ApiError::unprocessable("too_many_buckets", format!("The aggregation would return more than {} buckets, use a larger interval", MAX_BUCKETS))
+}
+
+/// Return the index of the range of `interval` containing `number`, none if the index doesn't fit in an i64.
+fn range_index(number : f64, interval : f64) -> Option<i64>
+{
+  let index = (number / interval).floor();
+  //i64::MAX is rounded up to 2^63 when converted, which is already out of range
+  (index >= i64::MIN as f64 && index < i64::MAX as f64).then(|| index as i64)
+}
+
+/// Count the values of `attribute` of the nodes in `nodes_id` grouped by `bucket`.
+pub fn aggregate(session : &Session, nodes_id : &[TreeNodeId], attribute : &str, bucket : &Bucket) -> Result<Aggregation, ApiError>
+{
+  let mut aggregation = Aggregation{ total : nodes_id.len(), missing : 0, other : 0, buckets : Vec::new() };
+  let values = nodes_id.iter().map(|node_id| node_value(session, *node_id, attribute));
+
+  match bucket
+  {
+    Bucket::Terms{ size } =>
+    {
+      let mut terms : HashMap<String, BucketCount> = HashMap::new();
+      for value in values
+      {
+        match value
+        {
+          Some(value) => terms.entry(value.to_string()).or_insert_with(|| BucketCount{ key : value, count : 0 }).count += 1,
+          None => aggregation.missing += 1,
+        }
+      }
+      let mut terms : Vec<(String, BucketCount)> = terms.into_iter().collect();
+      terms.sort_by(|(a_key, a), (b_key, b)| b.count.cmp(&a.count).then_with(|| a_key.cmp(b_key)));
+
+      let size = size.unwrap_or(TERMS_SIZE);
+      aggregation.other = terms.iter().skip(size).map(|(_, term)| term.count).sum();
+      aggregation.buckets = terms.into_iter().take(size).map(|(_, term)| term).collect();
+    },
+    Bucket::Histogram{ interval } =>
+    {
+      if !(*interval > 0.0 && interval.is_finite())
+      {
+        return Err(ApiError::unprocessable("invalid_interval", format!("Interval must be a positive number, got {}", interval)))
+      }
+      //keys are the index of the range so they can be ordered
+      let mut ranges : BTreeMap<i64, usize> = BTreeMap::new();
+      for value in values
+      {
+        match value.as_ref().and_then(Value::as_f64)
+        {
+          Some(number) =>
+          {
+            let index = range_index(number, *interval).ok_or_else(|| ApiError::unprocessable("invalid_interval",
+                          format!("Interval {} is too small for value {}", interval, number)))?;
+            *ranges.entry(index).or_default() += 1;
+            if ranges.len() > MAX_BUCKETS
+            {
+              return Err(too_many_buckets())
+            }
+          },
+          None => aggregation.missing += 1,
+        }
+      }
+      aggregation.buckets = ranges.into_iter().map(|(index, count)| BucketCount{ key : json!(index as f64 * interval), count }).collect();
+    },
+    Bucket::DateHistogram{ interval } =>
+    {
+      let mut periods : BTreeMap<DateTime<Utc>, usize> = BTreeMap::new();
+      for value in values
+      {
+        //dates are serialized in rfc3339
+        let date = value.as_ref().and_then(Value::as_str).and_then(|date| DateTime::parse_from_rfc3339(date).ok());
+        match date
+        {
+          Some(date) =>
+          {
+            *periods.entry(interval.truncate(date.with_timezone(&Utc))).or_default() += 1;
+            if periods.len() > MAX_BUCKETS
+            {
+              return Err(too_many_buckets())
+            }
+          },
+          None => aggregation.missing += 1,
+        }
+      }
+      aggregation.buckets = periods.into_iter().map(|(start, count)| BucketCount{ key : json!(start), count }).collect();
+    },
+  }
+
+  Ok(aggregation)
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn date(value : &str) -> DateTime<Utc>
+  {
+    DateTime::parse_from_rfc3339(value).unwrap().with_timezone(&Utc)
+  }
+
+  #[test]
+  fn truncate_to_start_of_period()
+  {
+    //2021-03-17 is a wednesday
+    let value = date("2021-03-17T14:35:12.250Z");
+
+    assert_eq!(DateInterval::Minute.truncate(value), date("2021-03-17T14:35:00Z"));
+    assert_eq!(DateInterval::Hour.truncate(value), date("2021-03-17T14:00:00Z"));
+    assert_eq!(DateInterval::Day.truncate(value), date("2021-03-17T00:00:00Z"));
+    assert_eq!(DateInterval::Week.truncate(value), date("2021-03-15T00:00:00Z"));
+    assert_eq!(DateInterval::Month.truncate(value), date("2021-03-01T00:00:00Z"));
+    assert_eq!(DateInterval::Year.truncate(value), date("2021-01-01T00:00:00Z"));
+  }
+
+  #[test]
+  fn truncate_week_across_month_and_year()
+  {
+    assert_eq!(DateInterval::Week.truncate(date("2021-01-02T10:00:00Z")), date("2020-12-28T00:00:00Z"));
+    assert_eq!(DateInterval::Week.truncate(date("2021-03-01T00:00:00Z")), date("2021-03-01T00:00:00Z"));
+  }
+
+  #[test]
+  fn range_index_of_values()
+  {
+    assert_eq!(range_index(0.0, 10.0), Some(0));
+    assert_eq!(range_index(25.0, 10.0), Some(2));
+    assert_eq!(range_index(-5.0, 10.0), Some(-1));
+  }
+
+  #[test]
+  fn range_index_out_of_range()
+  {
+    assert_eq!(range_index(1e300, 1e-9), None);
+    assert_eq!(range_index(-1e300, 1e-9), None);
+    assert_eq!(range_index(f64::MAX, 1e-300), None);
+  }
+}
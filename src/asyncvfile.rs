@@ -1,26 +1,151 @@
 use std::io::Read;
+use std::future::Future;
 
+use futures::ready;
 use futures::task::Context;
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Status};
 use rocket::{Request, Response};
 use rocket::response::{self, Responder};
+use rocket::tokio::task::JoinHandle;
 use rocket::tokio::io::{self, ReadBuf, AsyncRead};
 use rocket::tokio::macros::support::{Pin, Poll};
 
+/// Size of the buffers used to read the file.
+const CHUNK_SIZE : usize = 256 * 1024;
+
+/// Describe what part of a file is sent to the client.
+pub enum Content
+{
+  /// Data without file information.
+  Raw,
+  /// Whole file sent as an attachment.
+  File { name : String, size : u64, etag : Option<String> },
+  /// Attachment generated while it's sent, the size is not known in advance.
+  Attachment { name : String },
+  /// Inclusive range of a file of `size` bytes.
+  Range { name : String, start : u64, end : u64, size : u64, etag : String },
+  /// Multiple ranges sent as a multipart/byteranges body of `length` bytes.
+  MultiRange { name : String, boundary : String, length : u64, etag : String },
+  /// None of the requested ranges overlap the file of `size` bytes.
+  Unsatisfiable { size : u64 },
+}
+
 /**
  * Wrap VFile and implem AsyncRead.
+ * Each chunk of the file is read by a separate job on the blocking thread pool, 
+ * so a slow client never keep a blocking thread while it's not reading.
+ * The next chunk is read while the previous one is copied, and the two buffers are reused.
  */
 pub struct AsyncVFile
 {
-  file : Box<dyn Read + Sync + Send>,
-  info : Option<(String, u64)>,
+  state : ReadState,
+  content : Content,
+}
+
+type VFile = Box<dyn Read + Sync + Send>;
+
+/// File returned by a read job with the buffer it filled.
+type ReadResult = (VFile, Vec<u8>, io::Result<usize>);
+
+enum ReadState
+{
+  /// Nothing was read yet.
+  Idle(VFile),
+  Reading(ReadAhead),
+}
+
+/// Read `buffer` from `file` on the blocking pool.
+fn read_chunk(mut file : VFile, mut buffer : Vec<u8>) -> JoinHandle<ReadResult>
+{
+  rocket::tokio::task::spawn_blocking(move ||
+  {
+    buffer.resize(CHUNK_SIZE, 0);
+    let result = loop
+    {
+      match file.read(&mut buffer)
+      {
+        Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+        result => break result,
+      }
+    };
+    (file, buffer, result)
+  })
+}
+
+struct ReadAhead
+{
+  /// Chunk being read, none once the end of the file or an error was reached.
+  pending : Option<JoinHandle<ReadResult>>,
+  /// Buffer being copied and position of the next byte to copy.
+  current : Option<(Vec<u8>, usize)>,
+  /// Buffer that can be reused for the next read.
+  free : Option<Vec<u8>>,
+}
+
+impl ReadAhead
+{
+  fn start(file : VFile) -> Self
+  {
+    ReadAhead{ pending : Some(read_chunk(file, Vec::new())), current : None, free : None }
+  }
+
+  fn poll_read(&mut self, cx : &mut Context<'_>, buf : &mut ReadBuf<'_>) -> Poll<io::Result<()>>
+  {
+    loop
+    {
+      if let Some((chunk, pos)) = &mut self.current
+      {
+        let size = buf.remaining().min(chunk.len() - *pos);
+        buf.put_slice(&chunk[*pos..*pos + size]);
+        *pos += size;
+
+        if *pos == chunk.len()
+        {
+          self.free = self.current.take().map(|(chunk, _)| chunk);
+        }
+        return Poll::Ready(Ok(()))
+      }
+
+      let pending = match &mut self.pending
+      {
+        Some(pending) => pending,
+        //nothing is put in buf to signal the end of file
+        None => return Poll::Ready(Ok(())),
+      };
+      let result = ready!(Pin::new(pending).poll(cx));
+      self.pending = None;
+
+      match result
+      {
+        Ok((_, _, Ok(0))) => return Poll::Ready(Ok(())),
+        Ok((file, mut chunk, Ok(readed))) =>
+        {
+          chunk.truncate(readed);
+          self.current = Some((chunk, 0));
+          self.pending = Some(read_chunk(file, self.free.take().unwrap_or_default()));
+        },
+        Ok((_, _, Err(err))) => return Poll::Ready(Err(err)),
+        Err(err) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, err))),
+      }
+    }
+  }
 }
 
 impl AsyncVFile
 {
   pub fn new(file : Box<dyn Read + Sync + Send>, info : Option<(String , u64)>) -> AsyncVFile
   {
-    AsyncVFile{ file, info }
+    let content = match info
+    {
+      Some((name, size)) => Content::File{ name, size, etag : None },
+      None => Content::Raw,
+    };
+    AsyncVFile::with_content(file, content)
+  }
+
+  pub fn with_content(file : Box<dyn Read + Sync + Send>, content : Content) -> AsyncVFile
+  {
+    AsyncVFile{ state : ReadState::Idle(file), content }
   }
 }
 
@@ -31,52 +156,81 @@ impl Unpin for AsyncVFile
 /// AsyncRead implem for AsyncVFile wrapper.
 impl AsyncRead for AsyncVFile 
 {
-  #[allow(clippy::unit_arg)]
-  fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>>
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>>
   {
-    Poll::Ready(Ok(loop
+    let this = &mut *self;
+    if let ReadState::Idle(file) = &mut this.state
     {
-     let mut read_buff  = vec![0u8; buf.remaining()];
-      match self.file.read(&mut read_buff) 
-      {
-        Ok(e) => 
-        {
-          buf.put_slice(&read_buff[0..e]);
-          break ;
-        }
-        Err(ref e) if e.kind() == ::std::io::ErrorKind::Interrupted => 
-        {
-          continue;
-        }
-        Err(e) => 
-        {
-          return Poll::Ready(Err(e));
-        }
-      }
-    }))
+      //the first read is only launched when the body is streamed
+      let file = std::mem::replace(file, Box::new(std::io::empty()));
+      this.state = ReadState::Reading(ReadAhead::start(file));
+    }
+
+    match &mut this.state
+    {
+      ReadState::Reading(read_ahead) => read_ahead.poll_read(cx, buf),
+      ReadState::Idle(_) => unreachable!(),
+    }
   }
 }
 
+fn attachment(name : &str) -> String
+{
+  "attachment; filename=\"".to_owned() + name + "\""
+}
+
 impl<'r> Responder<'r, 'r> for AsyncVFile 
 {
   fn respond_to(self, _: &'r Request<'_>) -> response::Result<'r> 
   {
-    match &self.info
+    let mut response = Response::build();
+    response.header(ContentType::Binary);
+
+    match &self.content
     {
-      Some(info) => {
-        Response::build()
-            .header(ContentType::Binary)
-            .raw_header("Content-Disposition", "attachment; filename=\"".to_owned() + &info.0+ "\"")
-            .raw_header("Content-Length", info.1.to_string())
-            .streamed_body(self)
-            .ok()
-      }
-      None => {
-        Response::build()
-            .header(ContentType::Binary)
-            .streamed_body(self)
-            .ok()
-      }
+      Content::Raw => (),
+      Content::File{ name, size, etag } => 
+      {
+        response.raw_header("Content-Disposition", attachment(name))
+                .raw_header("Content-Length", size.to_string())
+                .raw_header("Accept-Ranges", "bytes");
+        if let Some(etag) = etag
+        {
+          response.raw_header("ETag", etag.clone());
+        }
+      },
+      Content::Attachment{ name } =>
+      {
+        response.raw_header("Content-Disposition", attachment(name));
+      },
+      Content::Range{ name, start, end, size, etag } =>
+      {
+        response.status(Status::PartialContent)
+                .raw_header("Content-Disposition", attachment(name))
+                .raw_header("Content-Range", format!("bytes {}-{}/{}", start, end, size))
+                .raw_header("Content-Length", (end - start + 1).to_string())
+                .raw_header("Accept-Ranges", "bytes")
+                .raw_header("ETag", etag.clone());
+      },
+      Content::MultiRange{ name, boundary, length, etag } =>
+      {
+        response.status(Status::PartialContent)
+                .raw_header("Content-Type", "multipart/byteranges; boundary=".to_owned() + boundary)
+                .raw_header("Content-Disposition", attachment(name))
+                .raw_header("Content-Length", length.to_string())
+                .raw_header("Accept-Ranges", "bytes")
+                .raw_header("ETag", etag.clone());
+      },
+      Content::Unsatisfiable{ size } =>
+      {
+        return Response::build()
+                 .status(Status::RangeNotSatisfiable)
+                 .raw_header("Content-Range", format!("bytes */{}", size))
+                 .raw_header("Accept-Ranges", "bytes")
+                 .ok()
+      },
     }
+
+    response.streamed_body(self).ok()
   }
 }
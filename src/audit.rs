@@ -0,0 +1,115 @@
+//! Append only audit trail of the actions done by the users on the server.
+
+use std::io;
+use std::sync::Arc;
+use std::path::Path;
+use std::fmt::Display;
+
+use log::warn;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+use rocket::tokio::task::spawn_blocking;
+
+use crate::auth::User;
+use crate::jsonlines::JsonLines;
+
+/// Result of an audited action.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum AuditOutcome
+{
+  Success { result : Value },
+  Error { error : String },
+}
+
+impl AuditOutcome
+{
+  pub fn success() -> Self
+  {
+    AuditOutcome::Success{ result : Value::Null }
+  }
+
+  pub fn error<E : Display>(err : E) -> Self
+  {
+    AuditOutcome::Error{ error : err.to_string() }
+  }
+
+  pub fn from_result<T : Serialize, E : Display>(result : &Result<T, E>) -> Self
+  {
+    match result
+    {
+      Ok(result) => AuditOutcome::Success{ result : json!(result) },
+      Err(err) => AuditOutcome::error(err),
+    }
+  }
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AuditEntry
+{
+  pub time : DateTime<Utc>,
+  pub user : String,
+  pub route : String,
+  pub arguments : Value,
+  pub outcome : AuditOutcome,
+}
+
+/// Filter used to select entries from the audit log.
+#[derive(Debug, Default)]
+pub struct AuditFilter
+{
+  pub user : Option<String>,
+  pub route : Option<String>,
+  pub after : Option<DateTime<Utc>>,
+  pub before : Option<DateTime<Utc>>,
+}
+
+impl AuditFilter
+{
+  fn matches(&self, entry : &AuditEntry) -> bool
+  {
+    self.user.as_ref().map_or(true, |user| *user == entry.user) &&
+    self.route.as_ref().map_or(true, |route| *route == entry.route) &&
+    self.after.map_or(true, |after| entry.time >= after) &&
+    self.before.map_or(true, |before| entry.time <= before)
+  }
+}
+
+/// Audit log written as JSON lines in a local file.
+/// The file is accessed from a blocking thread so the handlers don't block the server while it's written.
+pub struct AuditLog
+{
+  lines : Arc<JsonLines>,
+}
+
+impl AuditLog
+{
+  /// Open or create the audit log, existing entries are kept.
+  pub fn open<P : AsRef<Path>>(file_path : P) -> io::Result<Self>
+  {
+    Ok(AuditLog{ lines : Arc::new(JsonLines::open(file_path)?) })
+  }
+
+  /// Append an entry for an action done by `user`.
+  pub async fn record(&self, user : &User, route : &str, arguments : Value, outcome : AuditOutcome)
+  {
+    let entry = AuditEntry{ time : Utc::now(), user : user.name.clone(), route : route.into(), arguments, outcome };
+
+    let lines = self.lines.clone();
+    let result = spawn_blocking(move || lines.append(&entry)).await;
+    if let Err(err) = result.unwrap_or_else(|err| Err(err.into()))
+    {
+      warn!("Can't write audit entry to {} : {}", self.lines.path().display(), err);
+    }
+  }
+
+  /// Return all entries matching `filter`, in the order they were recorded.
+  pub async fn entries(&self, filter : AuditFilter) -> io::Result<Vec<AuditEntry>>
+  {
+    let lines = self.lines.clone();
+    spawn_blocking(move || lines.read(|entry| filter.matches(entry))).await?
+  }
+}
@@ -0,0 +1,125 @@
+//! Users, roles and the request guards used to check them.
+
+use sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
+
+use rocket::Request;
+use rocket::http::Status;
+use rocket::request::{Outcome, FromRequest};
+
+/// Role of a user, each role has all the rights of the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role
+{
+  /// Can browse the tree, run query and download data.
+  Viewer,
+  /// Can also upload files, run plugins and add attributes.
+  Analyst,
+  /// Can also delete nodes and load a saved session.
+  Admin,
+}
+
+/// A user as configured in the config file or in the users file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User
+{
+  pub name : String,
+  /// Hex encoded sha256 of the user API key.
+  pub key_hash : String,
+  pub role : Role,
+}
+
+/// Return the hex encoded sha256 of an API key as stored in `User::key_hash`.
+pub fn hash_key(key : &str) -> String
+{
+  format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// List of users allowed to access the server.
+pub struct Users
+{
+  users : Vec<User>,
+}
+
+impl Users
+{
+  pub fn new(users : Vec<User>) -> Self
+  {
+    Users{ users }
+  }
+
+  /// Return the user that own `key` if any.
+  pub fn authenticate(&self, key : &str) -> Option<&User>
+  {
+    let key_hash = hash_key(key);
+    self.users.iter().find(|user| user.key_hash.eq_ignore_ascii_case(&key_hash))
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = &User>
+  {
+    self.users.iter()
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiKeyError
+{
+  Missing,
+  Invalid,
+  Forbidden,
+}
+
+/// Fail the request and keep the reason in the request cache so the catcher can report it.
+fn failure(req : &Request<'_>, status : Status, err : ApiKeyError) -> Outcome<User, ApiKeyError>
+{
+  req.local_cache(|| Some(err));
+  Outcome::Failure((status, err))
+}
+
+/// Check the `x-api-key` header and return the user if it has at least `role`.
+fn authorize(req : &Request<'_>, role : Role) -> Outcome<User, ApiKeyError>
+{
+  let users = match req.rocket().state::<Users>()
+  {
+    Some(users) => users,
+    None => return Outcome::Failure((Status::InternalServerError, ApiKeyError::Missing)),
+  };
+
+  let key = match req.headers().get_one("x-api-key")
+  {
+    Some(key) => key,
+    None => return failure(req, Status::Unauthorized, ApiKeyError::Missing),
+  };
+
+  match users.authenticate(key)
+  {
+    Some(user) if user.role >= role => Outcome::Success(user.clone()),
+    Some(_) => failure(req, Status::Forbidden, ApiKeyError::Forbidden),
+    None => failure(req, Status::Unauthorized, ApiKeyError::Invalid),
+  }
+}
+
+macro_rules! role_guard
+{
+  ($guard:ident, $role:expr, $doc:expr) =>
+  {
+    #[doc = $doc]
+    pub struct $guard(pub User);
+
+    #[rocket::async_trait]
+    impl<'r> FromRequest<'r> for $guard
+    {
+      type Error = ApiKeyError;
+
+      async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error>
+      {
+        authorize(req, $role).map($guard)
+      }
+    }
+  };
+}
+
+role_guard!(Viewer, Role::Viewer, "Request guard for an user with at least the viewer role.");
+role_guard!(Analyst, Role::Analyst, "Request guard for an user with at least the analyst role.");
+role_guard!(Admin, Role::Admin, "Request guard for an user with the admin role.");
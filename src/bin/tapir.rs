@@ -2,16 +2,22 @@
 //! on data then output attributes and data to a tree, that can be accessed by a distant client trough a REST API
 
 use std::env;
+use std::process;
 use std::fs::File;
+use std::thread;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::io::{self, Read};
 
 use tap::session::{Session};
 
 use tapir::server::{serve, Arguments};
+use tapir::auth::{User, Role, hash_key};
+use tapir::pipeline::PipelineRule;
 
-use log::warn;
+use log::{info, warn};
 use dotenv::dotenv;
+use serde::de::DeserializeOwned;
 use serde_derive::Deserialize;
 use clap::{crate_authors, crate_description, crate_name, crate_version, Arg, App};
 
@@ -20,7 +26,42 @@ struct Config
 {
   address : SocketAddr,
   upload : String,
-  api_key : String,
+  api_key : Option<String>,
+  #[serde(default)]
+  users : Vec<User>,
+  users_file : Option<String>,
+  audit : Option<String>,
+  #[serde(default)]
+  evidence_roots : Vec<String>,
+  tls_cert : Option<String>,
+  tls_key : Option<String>,
+  tls_self_signed : Option<bool>,
+  max_tasks : Option<usize>,
+  #[serde(default)]
+  concurrency : HashMap<String, usize>,
+  #[serde(default)]
+  pipelines : Vec<PipelineRule>,
+}
+
+#[derive(Deserialize)]
+struct UsersFile
+{
+  users : Vec<User>,
+}
+
+/// Read and deserialize a toml file.
+fn read_toml<T : DeserializeOwned>(file_path : &str) -> io::Result<T>
+{
+  File::open(file_path)
+    .and_then(|mut file| 
+    {
+      let mut buffer = String::new();
+      file.read_to_string(&mut buffer)?;
+      Ok(buffer)
+    })
+    .and_then(|buffer| 
+       toml::from_str::<T>(&buffer)
+       .map_err(|err| io::Error::new(io::ErrorKind::Other, err)))
 }
 
 /// We first check argument in this order if not found : command line, environment, config file, then default value 
@@ -52,24 +93,62 @@ fn usage() -> Arguments
       .short("k")
       .long("apikey")
       .value_name("APIKEY")
-      .help("API key")
+      .help("API key, only used if no users are configured")
+      .takes_value(true))
+    .arg(Arg::with_name("users")
+      .long("users")
+      .value_name("FILE")
+      .help("Users file")
+      .takes_value(true))
+    .arg(Arg::with_name("audit")
+      .long("audit")
+      .value_name("FILE")
+      .help("Path to the audit log file")
+      .takes_value(true))
+    .arg(Arg::with_name("evidence-root")
+      .short("e")
+      .long("evidence-root")
+      .value_name("DIR")
+      .help("Directory from which the local plugin can load files (can be repeated)")
+      .multiple(true)
+      .number_of_values(1)
+      .takes_value(true))
+    .arg(Arg::with_name("tls-cert")
+      .long("tls-cert")
+      .value_name("FILE")
+      .help("Path to the TLS certificate in pem format")
+      .takes_value(true))
+    .arg(Arg::with_name("tls-key")
+      .long("tls-key")
+      .value_name("FILE")
+      .help("Path to the TLS private key in pem format")
+      .takes_value(true))
+    .arg(Arg::with_name("tls-self-signed")
+      .long("tls-self-signed")
+      .help("Generate a self-signed certificate if the certificate or key doesn't exist"))
+    .arg(Arg::with_name("max-tasks")
+      .long("max-tasks")
+      .value_name("COUNT")
+      .help("Maximum number of tasks running at the same time")
+      .takes_value(true))
+    .arg(Arg::with_name("hash-key")
+      .long("hash-key")
+      .value_name("APIKEY")
+      .help("Print the hash of an API key to use in a users file and exit")
       .takes_value(true))
     .get_matches();
 
+  if let Some(key) = matches.value_of("hash-key")
+  {
+    println!("{}", hash_key(key));
+    process::exit(0);
+  }
+
   let config_file = matches.value_of("config")
     .map(|s| s.to_owned())
     .or_else(|| Some(String::from("tapir.toml"))).unwrap();
 
-  let config = File::open(config_file)
-    .and_then(|mut file| 
-    {
-      let mut buffer = String::new();
-      file.read_to_string(&mut buffer)?;
-      Ok(buffer)
-    })
-    .and_then(|buffer| 
-       toml::from_str::<Config>(&buffer)
-       .map_err(|err| io::Error::new(io::ErrorKind::Other, err)))
+  let config = read_toml::<Config>(&config_file)
     .map_err(|err| warn!("Can't read config file: {}", err))
     .ok();
 
@@ -87,21 +166,99 @@ fn usage() -> Arguments
     .or_else(|| config.clone().map(|config| config.upload))
     .or_else(|| Some(String::from("./upload"))).unwrap();
 
-  let api_key = matches.value_of("apikey")
+  let api_key = matches.value_of("key")
     .map(|s| s.to_owned())
     .or_else(|| env::var("TAPIR_APIKEY").ok())
-    .or_else(|| config.clone().map(|config| config.api_key))
-    .or_else(|| Some(String::from("key"))).unwrap();
+    .or_else(|| config.clone().and_then(|config| config.api_key));
+
+  let audit = matches.value_of("audit")
+    .map(|s| s.to_owned())
+    .or_else(|| env::var("TAPIR_AUDIT").ok())
+    .or_else(|| config.clone().and_then(|config| config.audit))
+    .or_else(|| Some(String::from("./audit.jsonl"))).unwrap();
+
+  let evidence_roots = matches.values_of("evidence-root")
+    .map(|values| values.map(|s| s.to_owned()).collect())
+    .or_else(|| env::var_os("TAPIR_EVIDENCE_ROOTS")
+      .map(|roots| env::split_paths(&roots).map(|root| root.to_string_lossy().into_owned()).collect()))
+    .or_else(|| config.clone().map(|config| config.evidence_roots))
+    .unwrap_or_default();
+
+  let tls_self_signed = matches.is_present("tls-self-signed") ||
+    env::var("TAPIR_TLS_SELF_SIGNED").ok()
+    .and_then(|value| value.parse::<bool>().ok())
+    .or_else(|| config.clone().and_then(|config| config.tls_self_signed))
+    .unwrap_or(false);
+
+  let tls_cert = matches.value_of("tls-cert")
+    .map(|s| s.to_owned())
+    .or_else(|| env::var("TAPIR_TLS_CERT").ok())
+    .or_else(|| config.clone().and_then(|config| config.tls_cert))
+    .or_else(|| tls_self_signed.then(|| String::from("./tapir-cert.pem")));
+
+  let tls_key = matches.value_of("tls-key")
+    .map(|s| s.to_owned())
+    .or_else(|| env::var("TAPIR_TLS_KEY").ok())
+    .or_else(|| config.clone().and_then(|config| config.tls_key))
+    .or_else(|| tls_self_signed.then(|| String::from("./tapir-key.pem")));
+
+  let max_tasks = matches.value_of("max-tasks")
+    .map(|s| s.to_owned())
+    .or_else(|| env::var("TAPIR_MAX_TASKS").ok())
+    .and_then(|count| count.parse().ok())
+    .or_else(|| config.clone().and_then(|config| config.max_tasks))
+    .or_else(|| thread::available_parallelism().map(|count| count.get()).ok())
+    .unwrap_or(4);
+
+  let concurrency = config.clone().map(|config| config.concurrency).unwrap_or_default();
+  let pipelines = config.clone().map(|config| config.pipelines).unwrap_or_default();
+
+  let users_file = matches.value_of("users")
+    .map(|s| s.to_owned())
+    .or_else(|| env::var("TAPIR_USERS").ok())
+    .or_else(|| config.clone().and_then(|config| config.users_file));
+
+  let mut users = config.map(|config| config.users).unwrap_or_default();
+  if let Some(users_file) = users_file
+  {
+    match read_toml::<UsersFile>(&users_file)
+    {
+      Ok(users_file) => users.extend(users_file.users),
+      Err(err) => 
+      {
+        eprintln!("Can't read users file {} : {}", users_file, err);
+        process::exit(1);
+      }
+    }
+  }
+
+  //without users we fallback to the shared api key
+  if users.is_empty()
+  {
+    match api_key
+    {
+      Some(api_key) =>
+      {
+        info!("No users configured, using shared API key");
+        users.push(User{ name : String::from("admin"), key_hash : hash_key(&api_key), role : Role::Admin });
+      },
+      None =>
+      {
+        eprintln!("No API key configured, set api_key or add users in the config file");
+        process::exit(1);
+      },
+    }
+  }
 
-  Arguments{address, upload, api_key}
+  Arguments{address, upload, users, audit, evidence_roots, tls_cert, tls_key, tls_self_signed, max_tasks, concurrency, pipelines}
 }
 
 /// register different plugins that will be available from the server
-/// /!\ local let user load any file on the filesystem remotely,
-/// it must be used only for test or in a sandboxed env
+/// local let user load files from the server filesystem,
+/// the server only allow it to access files in the evidence roots
 fn register_plugins(session :&mut Session)
 {
-  session.plugins_db.register(Box::new(tap_plugin_local::Plugin::new())); // /!\ dangerous if not sandboxed XXX
+  session.plugins_db.register(Box::new(tap_plugin_local::Plugin::new())); // restricted to evidence roots by the server
   session.plugins_db.register(Box::new(tap_plugin_exif::Plugin::new())); 
   session.plugins_db.register(Box::new(tap_plugin_hash::Plugin::new())); 
   session.plugins_db.register(Box::new(tap_plugin_s3::Plugin::new())); 
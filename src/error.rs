@@ -0,0 +1,165 @@
+//! Error returned by the routes and the catchers, always sent as a JSON object
+//! `{code, message, details}` so clients can handle failures programmatically.
+
+use std::io;
+use std::fmt;
+
+use serde::Serialize;
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use rocket::{Catcher, Request};
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::response::status::Custom;
+use rocket::serde::json::Json;
+use rocket::tokio::task::JoinError;
+
+use crate::auth::ApiKeyError;
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ApiError
+{
+  #[serde(skip)]
+  pub status : Status,
+  /// Stable identifier of the error, like `node_not_found`.
+  pub code : &'static str,
+  pub message : String,
+  pub details : Option<Value>,
+}
+
+impl ApiError
+{
+  pub fn new<M : Into<String>>(status : Status, code : &'static str, message : M) -> Self
+  {
+    ApiError{ status, code, message : message.into(), details : None }
+  }
+
+  pub fn with_details(mut self, details : Value) -> Self
+  {
+    self.details = Some(details);
+    self
+  }
+
+  pub fn bad_request<M : Into<String>>(code : &'static str, message : M) -> Self
+  {
+    ApiError::new(Status::BadRequest, code, message)
+  }
+
+  pub fn not_found<M : Into<String>>(code : &'static str, message : M) -> Self
+  {
+    ApiError::new(Status::NotFound, code, message)
+  }
+
+  pub fn unprocessable<M : Into<String>>(code : &'static str, message : M) -> Self
+  {
+    ApiError::new(Status::UnprocessableEntity, code, message)
+  }
+
+  pub fn unauthorized() -> Self
+  {
+    ApiError::new(Status::Unauthorized, "unauthorized", "Missing or invalid API key")
+  }
+
+  pub fn node_not_found() -> Self
+  {
+    ApiError::not_found("node_not_found", "Node not found")
+  }
+
+  pub fn internal<M : Into<String>>(message : M) -> Self
+  {
+    ApiError::new(Status::InternalServerError, "internal_error", message)
+  }
+}
+
+impl fmt::Display for ApiError
+{
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result
+  {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl From<io::Error> for ApiError
+{
+  fn from(err : io::Error) -> Self
+  {
+    let (status, code) = match err.kind()
+    {
+      io::ErrorKind::NotFound => (Status::NotFound, "not_found"),
+      io::ErrorKind::AlreadyExists => (Status::Conflict, "conflict"),
+      io::ErrorKind::InvalidInput => (Status::BadRequest, "bad_request"),
+      io::ErrorKind::InvalidData => (Status::UnprocessableEntity, "invalid_data"),
+      io::ErrorKind::PermissionDenied => (Status::Forbidden, "forbidden"),
+      //anything else is a failure of the server, like a full disk
+      _ => (Status::InternalServerError, "internal_error"),
+    };
+    ApiError::new(status, code, err.to_string())
+  }
+}
+
+/// The thread handling the request panicked or was cancelled.
+impl From<JoinError> for ApiError
+{
+  fn from(err : JoinError) -> Self
+  {
+    ApiError::internal(format!("Request failed : {}", err))
+  }
+}
+
+/// Only used when serializing a response, so it's an error of the server.
+impl From<serde_json::Error> for ApiError
+{
+  fn from(err : serde_json::Error) -> Self
+  {
+    ApiError::internal(format!("Can't serialize response : {}", err))
+  }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError
+{
+  fn respond_to(self, req : &'r Request<'_>) -> response::Result<'static>
+  {
+    Custom(self.status, Json(self)).respond_to(req)
+  }
+}
+
+/// Return the error code used by the catchers for `status`.
+fn status_code(status : Status) -> &'static str
+{
+  match status.code
+  {
+    400 => "bad_request",
+    401 => "unauthorized",
+    403 => "forbidden",
+    404 => "not_found",
+    409 => "conflict",
+    413 => "payload_too_large",
+    415 => "unsupported_media_type",
+    422 => "unprocessable_entity",
+    500 => "internal_error",
+    _ => "error",
+  }
+}
+
+/// Catch the errors that are not returned by a route, like guards and data failures.
+#[catch(default)]
+fn default_catcher(status : Status, req : &Request<'_>) -> ApiError
+{
+  //the authentication guards keep the reason of the failure in the request cache
+  let message = match req.local_cache(|| None::<ApiKeyError>)
+  {
+    Some(ApiKeyError::Missing) => "Missing API key".to_string(),
+    Some(ApiKeyError::Invalid) => "Invalid API key".to_string(),
+    Some(ApiKeyError::Forbidden) => "The user role doesn't allow this action".to_string(),
+    None => status.reason().unwrap_or("Error").to_string(),
+  };
+
+  ApiError::new(status, status_code(status), message)
+}
+
+/// Catchers to register on the server so every error is sent as JSON.
+pub fn catchers() -> Vec<Catcher>
+{
+  catchers![default_catcher]
+}
@@ -0,0 +1,332 @@
+//! Events sent to the clients when tasks change state or the tree is modified.
+
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::json;
+use rocket::tokio::sync::broadcast;
+
+use tap::tree::TreeNodeId;
+
+use crate::server::ArcSession;
+use crate::taskqueue::QueuedTask;
+
+/// Number of events kept for a client that read them too slowly, older events are dropped.
+const EVENTS_CAPACITY : usize = 1024;
+/// Interval at which the tree is checked for new nodes.
+const TREE_WATCH_INTERVAL : Duration = Duration::from_secs(1);
+/// Number of missing nodes after which the scan for new nodes stops, nodes removed before being found leave holes.
+const NODE_SCAN_GAP : usize = 1024;
+/// Maximum number of nodes sent in a single `nodes_created` event.
+const NODES_PER_EVENT : usize = 512;
+
+/// A node added to the tree.
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedNode
+{
+  pub node_id : TreeNodeId,
+  pub path : String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event
+{
+  /// A task changed state, `path` is the path of the node the task is applied to.
+  Task { task : QueuedTask, path : Option<String> },
+  NodeDeleted { node_id : TreeNodeId, path : String },
+  Attribute { node_id : TreeNodeId, path : String, name : String },
+  /// Nodes added to the tree, by a plugin or by `load`.
+  NodesCreated { nodes : Vec<CreatedNode> },
+  /// Nodes were added or removed from the tree.
+  NodeCount { count : usize, added : i64 },
+}
+
+impl Event
+{
+  pub fn name(&self) -> &'static str
+  {
+    match self
+    {
+      Event::Task{ .. } => "task",
+      Event::NodeDeleted{ .. } => "node_deleted",
+      Event::Attribute{ .. } => "attribute",
+      Event::NodesCreated{ .. } => "nodes_created",
+      Event::NodeCount{ .. } => "node_count",
+    }
+  }
+
+  /// Return true if the event is about the task with `task_id` in the queue or on the session task scheduler.
+  fn has_task(&self, task_id : u32) -> bool
+  {
+    match self
+    {
+      Event::Task{ task, .. } => task.id == task_id || task.tap_id == Some(task_id),
+      _ => false,
+    }
+  }
+
+  fn path(&self) -> Option<&str>
+  {
+    match self
+    {
+      Event::Task{ path, .. } => path.as_deref(),
+      Event::NodeDeleted{ path, .. } | Event::Attribute{ path, .. } => Some(path),
+      Event::NodesCreated{ .. } | Event::NodeCount{ .. } => None,
+    }
+  }
+}
+
+/// Return true if `path` is `root` or one of it's descendants.
+fn is_under(path : &str, root : &str) -> bool
+{
+  path == root || path.starts_with(&(root.trim_end_matches('/').to_owned() + "/"))
+}
+
+/// Events selected by a client, events that don't concern a task are dropped when a task is selected,
+/// and events about nodes outside of the `root` subtree are dropped when a root is set.
+#[derive(Debug, Default)]
+pub struct EventFilter
+{
+  pub task_id : Option<u32>,
+  /// Only send events on the nodes of this subtree.
+  pub root : Option<String>,
+}
+
+impl EventFilter
+{
+  /// Return the event to send to the client, `nodes_created` events only keep the nodes of the `root` subtree.
+  pub fn select(&self, event : &Event) -> Option<Event>
+  {
+    if let Some(task_id) = self.task_id
+    {
+      if !event.has_task(task_id)
+      {
+        return None
+      }
+    }
+
+    match (&self.root, event)
+    {
+      (Some(root), Event::NodesCreated{ nodes }) =>
+      {
+        let nodes : Vec<CreatedNode> = nodes.iter().filter(|node| is_under(&node.path, root)).cloned().collect();
+        (!nodes.is_empty()).then(|| Event::NodesCreated{ nodes })
+      },
+      (Some(root), event) => match event.path()
+      {
+        Some(path) if !is_under(path, root) => None,
+        //events without path, like the node count or tasks not applied to a node, are not about a subtree
+        _ => Some(event.clone()),
+      },
+      (None, event) => Some(event.clone()),
+    }
+  }
+}
+
+/// Broadcast events to all the connected clients.
+#[derive(Clone)]
+pub struct Events
+{
+  sender : broadcast::Sender<Event>,
+}
+
+impl Events
+{
+  pub fn new() -> Self
+  {
+    let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+    Events{ sender }
+  }
+
+  pub fn send(&self, event : Event)
+  {
+    //there is no error if no client is connected
+    let _ = self.sender.send(event);
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<Event>
+  {
+    self.sender.subscribe()
+  }
+
+  /// Send the nodes created and the node count each time the tree change.
+  /// Tap doesn't notify when plugins add nodes to the tree, so the node count is checked at regular interval
+  /// and the new nodes are searched only when it changed.
+  pub fn watch_tree(&self, session : ArcSession)
+  {
+    let events = self.clone();
+    thread::spawn(move ||
+    {
+      let mut last_count = session.tree.count();
+      let mut next_index = scan_nodes(&session, 1, |_| ());
+      loop
+      {
+        thread::sleep(TREE_WATCH_INTERVAL);
+
+        let count = session.tree.count();
+        if count == last_count
+        {
+          continue
+        }
+
+        let mut nodes = Vec::new();
+        next_index = scan_nodes(&session, next_index, |node_id| 
+        {
+          if let Some(path) = session.tree.node_path(node_id)
+          {
+            nodes.push(CreatedNode{ node_id, path });
+          }
+        });
+        while !nodes.is_empty()
+        {
+          let rest = nodes.split_off(nodes.len().min(NODES_PER_EVENT));
+          events.send(Event::NodesCreated{ nodes });
+          nodes = rest;
+        }
+
+        events.send(Event::NodeCount{ count, added : count as i64 - last_count as i64 });
+        last_count = count;
+      }
+    });
+  }
+}
+
+/// Return the id of the node stored at `index` in the tree, if it's place was never used by a removed node.
+/// Tap has no API to list the nodes added since a given node, so this rely on node ids being an index in the tree
+/// and a stamp incremented when the place of a removed node is reused.
+fn node_id_at(index : usize) -> Option<TreeNodeId>
+{
+  serde_json::from_value(json!({"index1" : index, "stamp" : 0})).ok()
+}
+
+/// Call `found` on the nodes added at the end of the tree from `index`, and return the index following the last node found.
+/// Nodes are added at the end of the tree, except when they take the place of a removed node, those are only counted by `node_count`.
+fn scan_nodes<F : FnMut(TreeNodeId)>(session : &ArcSession, index : usize, mut found : F) -> usize
+{
+  let mut next = index;
+  let mut index = index;
+  while index < next + NODE_SCAN_GAP
+  {
+    if let Some(node_id) = node_id_at(index).filter(|node_id| session.tree.get_node_from_id(*node_id).is_some())
+    {
+      found(node_id);
+      next = index + 1;
+    }
+    index += 1;
+  }
+  next
+}
+
+impl Default for Events
+{
+  fn default() -> Self
+  {
+    Events::new()
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+  use chrono::Utc;
+  use crate::taskqueue::{TaskOrigin, TaskProgress, TaskStatus};
+
+  fn task(id : u32, tap_id : Option<u32>, path : Option<&str>) -> Event
+  {
+    let task = serde_json::from_value(json!({"id" : id, "tap_id" : tap_id, "origin" : TaskOrigin::Queue, "plugin" : "hash", "argument" : null,
+                                             "relaunch" : false, "priority" : 0, "state" : TaskStatus::Waiting, "cancel_requested" : false,
+                                             "progress" : TaskProgress::default(), "queued" : Utc::now()})).unwrap();
+    Event::Task{ task, path : path.map(String::from) }
+  }
+
+  fn deleted(index : usize, path : &str) -> Event
+  {
+    Event::NodeDeleted{ node_id : node_id_at(index).unwrap(), path : path.into() }
+  }
+
+  fn created(paths : &[&str]) -> Event
+  {
+    let nodes = paths.iter().enumerate().map(|(index, path)| CreatedNode{ node_id : node_id_at(index + 1).unwrap(), path : path.to_string() }).collect();
+    Event::NodesCreated{ nodes }
+  }
+
+  fn created_paths(event : Option<Event>) -> Option<Vec<String>>
+  {
+    match event
+    {
+      Some(Event::NodesCreated{ nodes }) => Some(nodes.into_iter().map(|node| node.path).collect()),
+      _ => None,
+    }
+  }
+
+  fn subtree(root : &str) -> EventFilter
+  {
+    EventFilter{ task_id : None, root : Some(root.into()) }
+  }
+
+  #[test]
+  fn no_filter_keeps_every_event()
+  {
+    let filter = EventFilter::default();
+    assert!(filter.select(&task(1, None, None)).is_some());
+    assert!(filter.select(&deleted(1, "/root/a")).is_some());
+    assert_eq!(created_paths(filter.select(&created(&["/root/a", "/root/b"]))), Some(vec!["/root/a".into(), "/root/b".into()]));
+  }
+
+  #[test]
+  fn task_filter_keeps_the_task_by_queue_or_scheduler_id()
+  {
+    let filter = EventFilter{ task_id : Some(7), root : None };
+    assert!(filter.select(&task(7, None, None)).is_some());
+    assert!(filter.select(&task(8, Some(7), None)).is_some());
+    assert!(filter.select(&task(8, Some(9), None)).is_none());
+    assert!(filter.select(&deleted(1, "/root/a")).is_none());
+    assert!(filter.select(&Event::NodeCount{ count : 2, added : 1 }).is_none());
+  }
+
+  #[test]
+  fn subtree_filter_drops_events_outside_of_root()
+  {
+    let filter = subtree("/root/disk");
+    assert!(filter.select(&deleted(1, "/root/disk")).is_some());
+    assert!(filter.select(&deleted(1, "/root/disk/file")).is_some());
+    assert!(filter.select(&deleted(1, "/root/other")).is_none());
+    assert!(filter.select(&task(1, None, Some("/root/disk/file"))).is_some());
+    assert!(filter.select(&task(1, None, Some("/root/other"))).is_none());
+  }
+
+  #[test]
+  fn subtree_filter_doesnt_match_a_sibling_with_the_same_prefix()
+  {
+    assert!(subtree("/root/disk").select(&deleted(1, "/root/disk2/file")).is_none());
+    assert!(subtree("/root/disk/").select(&deleted(1, "/root/disk/file")).is_some());
+  }
+
+  #[test]
+  fn subtree_filter_keeps_events_without_path()
+  {
+    let filter = subtree("/root/disk");
+    assert!(filter.select(&Event::NodeCount{ count : 2, added : 1 }).is_some());
+    assert!(filter.select(&task(1, None, None)).is_some());
+  }
+
+  #[test]
+  fn subtree_filter_keeps_the_created_nodes_of_the_subtree()
+  {
+    let filter = subtree("/root/disk");
+    let event = created(&["/root/disk/a", "/root/other", "/root/disk/b"]);
+    assert_eq!(created_paths(filter.select(&event)), Some(vec!["/root/disk/a".into(), "/root/disk/b".into()]));
+    assert!(filter.select(&created(&["/root/other"])).is_none());
+  }
+
+  #[test]
+  fn task_and_subtree_filters_are_combined()
+  {
+    let filter = EventFilter{ task_id : Some(7), root : Some("/root/disk".into()) };
+    assert!(filter.select(&task(7, None, Some("/root/disk/file"))).is_some());
+    assert!(filter.select(&task(7, None, Some("/root/other"))).is_none());
+  }
+}
@@ -0,0 +1,366 @@
+//! Export of nodes data in a zip or tar archive, written in a blocking thread and streamed to the client.
+
+use std::sync::Mutex;
+use std::collections::HashSet;
+use std::io::{self, Cursor, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use zip::ZipWriter;
+use zip::CompressionMethod;
+use zip::write::{SimpleFileOptions, StreamWriter};
+
+use tap::session::Session;
+use tap::tree::TreeNodeId;
+
+use crate::server::ArcSession;
+use crate::asyncvfile::{AsyncVFile, Content};
+
+/// Size of the chunks sent from the archive thread to the client.
+const PIPE_CHUNK_SIZE : usize = 256 * 1024;
+/// Number of chunks that can wait to be sent.
+const PIPE_CAPACITY : usize = 4;
+/// Name of the manifest added at the end of the archive.
+const MANIFEST_NAME : &str = "manifest.json";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat
+{
+  Zip,
+  Tar,
+}
+
+impl ArchiveFormat
+{
+  fn file_name(&self) -> &'static str
+  {
+    match self
+    {
+      ArchiveFormat::Zip => "export.zip",
+      ArchiveFormat::Tar => "export.tar",
+    }
+  }
+}
+
+/// Write side of the pipe, data is sent by chunks to the reader.
+struct PipeWriter
+{
+  sender : SyncSender<io::Result<Vec<u8>>>,
+  buffer : Vec<u8>,
+}
+
+impl PipeWriter
+{
+  fn send_buffer(&mut self) -> io::Result<()>
+  {
+    if self.buffer.is_empty()
+    {
+      return Ok(())
+    }
+
+    let buffer = std::mem::replace(&mut self.buffer, Vec::with_capacity(PIPE_CHUNK_SIZE));
+    self.sender.send(Ok(buffer)).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Export stream closed"))
+  }
+}
+
+impl Write for PipeWriter
+{
+  fn write(&mut self, buf : &[u8]) -> io::Result<usize>
+  {
+    self.buffer.extend_from_slice(buf);
+    if self.buffer.len() >= PIPE_CHUNK_SIZE
+    {
+      self.send_buffer()?;
+    }
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()>
+  {
+    self.send_buffer()
+  }
+}
+
+impl Drop for PipeWriter
+{
+  fn drop(&mut self)
+  {
+    let _ = self.send_buffer();
+  }
+}
+
+/// Read side of the pipe, return the end of file once the writer is dropped.
+struct PipeReader
+{
+  receiver : Mutex<Receiver<io::Result<Vec<u8>>>>,
+  current : Cursor<Vec<u8>>,
+}
+
+impl Read for PipeReader
+{
+  fn read(&mut self, buf : &mut [u8]) -> io::Result<usize>
+  {
+    loop
+    {
+      let readed = self.current.read(buf)?;
+      if readed > 0 || buf.is_empty()
+      {
+        return Ok(readed)
+      }
+
+      match self.receiver.get_mut().unwrap().recv()
+      {
+        Ok(chunk) => self.current = Cursor::new(chunk?),
+        Err(_) => return Ok(0),
+      }
+    }
+  }
+}
+
+/// An archive in which files can be added one after the other.
+trait Archive
+{
+  fn add(&mut self, path : &str, size : u64, data : &mut dyn Read) -> io::Result<()>;
+  fn finish(self : Box<Self>) -> io::Result<()>;
+}
+
+struct ZipArchive(ZipWriter<StreamWriter<PipeWriter>>);
+
+impl Archive for ZipArchive
+{
+  fn add(&mut self, path : &str, size : u64, data : &mut dyn Read) -> io::Result<()>
+  {
+    //data is stored as is, most evidence are already compressed
+    let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Stored)
+                    .large_file(size >= u32::MAX as u64);
+    self.0.start_file(path, options)?;
+    io::copy(data, &mut self.0)?;
+    Ok(())
+  }
+
+  fn finish(self : Box<Self>) -> io::Result<()>
+  {
+    self.0.finish()?.flush()
+  }
+}
+
+struct TarArchive(tar::Builder<PipeWriter>);
+
+impl Archive for TarArchive
+{
+  fn add(&mut self, path : &str, size : u64, data : &mut dyn Read) -> io::Result<()>
+  {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    self.0.append_data(&mut header, path, data)
+  }
+
+  fn finish(self : Box<Self>) -> io::Result<()>
+  {
+    self.0.into_inner()?.flush()
+  }
+}
+
+/// Reader returning exactly `size` bytes of `inner`, the archive headers are written before the data
+/// so a read error or a file shorter than expected is padded with zeros and recorded instead of corrupting the archive.
+struct SizedReader<'a>
+{
+  inner : &'a mut dyn Read,
+  remaining : u64,
+  size : u64,
+  error : Option<String>,
+}
+
+impl<'a> SizedReader<'a>
+{
+  fn new(inner : &'a mut dyn Read, size : u64) -> Self
+  {
+    SizedReader{ inner, remaining : size, size, error : None }
+  }
+
+  /// Return the error to add to the manifest if the data was not read entirely.
+  fn error(mut self) -> Option<String>
+  {
+    if self.error.is_none()
+    {
+      let mut byte = [0u8; 1];
+      if let Ok(1) = self.inner.read(&mut byte)
+      {
+        self.error = Some(format!("Data is larger than {} bytes, it was truncated", self.size));
+      }
+    }
+    self.error
+  }
+}
+
+impl Read for SizedReader<'_>
+{
+  fn read(&mut self, buf : &mut [u8]) -> io::Result<usize>
+  {
+    let len = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+    if len == 0
+    {
+      return Ok(0)
+    }
+
+    let readed = match self.error
+    {
+      Some(_) => 0,
+      None => loop
+      {
+        match self.inner.read(&mut buf[..len])
+        {
+          Ok(0) =>
+          {
+            self.error = Some(format!("Data is shorter than {} bytes, it was padded with zeros from offset {}", self.size, self.size - self.remaining));
+            break 0
+          },
+          Ok(readed) => break readed,
+          Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+          Err(err) =>
+          {
+            self.error = Some(format!("Read error at offset {}, data was padded with zeros : {}", self.size - self.remaining, err));
+            break 0
+          },
+        }
+      },
+    };
+
+    let readed = match readed
+    {
+      0 => { buf[..len].fill(0); len },
+      readed => readed,
+    };
+    self.remaining -= readed as u64;
+    Ok(readed)
+  }
+}
+
+/// Entry of the manifest describing an exported node.
+#[derive(Serialize)]
+struct ManifestEntry
+{
+  id : TreeNodeId,
+  path : Option<String>,
+  size : Option<u64>,
+  error : Option<String>,
+}
+
+/// Return a name that can be used as a single component of an archive path.
+fn sanitize_component(name : &str) -> Option<String>
+{
+  let name = name.replace(['/', '\\', '\0'], "_");
+  match name.as_str()
+  {
+    "" | "." | ".." => None,
+    _ => Some(name),
+  }
+}
+
+/// Return a path in the archive for `node_id` that is not already used.
+/// The path is built from the nodes names so a name containing a separator or `..` can't escape the extraction directory.
+fn archive_path(session : &Session, node_id : TreeNodeId, paths : &mut HashSet<String>) -> Result<String, String>
+{
+  let mut components = Vec::new();
+  let mut current = Some(node_id);
+  while let Some(id) = current
+  {
+    let node = session.tree.get_node_from_id(id).ok_or_else(|| "Invalid NodeId".to_string())?;
+    current = session.tree.parent_id(id);
+    //the root node name is not part of the path
+    if current.is_some()
+    {
+      components.extend(sanitize_component(&node.name()));
+    }
+  }
+  components.reverse();
+
+  let node_path = match components.is_empty()
+  {
+    true => "node".to_string(),
+    false => components.join("/"),
+  };
+
+  let mut path = node_path.clone();
+  let mut index = 0;
+  while paths.contains(&path) || path == MANIFEST_NAME
+  {
+    index += 1;
+    path = format!("{} ({})", node_path, index);
+  }
+  paths.insert(path.clone());
+  Ok(path)
+}
+
+fn write_archive(session : &Session, nodes_id : &[TreeNodeId], mut archive : Box<dyn Archive>) -> io::Result<()>
+{
+  let mut manifest = Vec::with_capacity(nodes_id.len());
+  let mut paths = HashSet::new();
+
+  for node_id in nodes_id
+  {
+    let mut entry = ManifestEntry{ id : *node_id, path : None, size : None, error : None };
+
+    let opened = session.tree.get_node_from_id(*node_id).ok_or_else(|| "Invalid NodeId".to_string())
+      .and_then(|node| node.value().get_value("data").ok_or_else(|| "No data attribute on node".to_string()))
+      .and_then(|attr|
+      {
+        let builder = attr.as_vfile_builder();
+        let file = builder.open().map_err(|err| err.to_string())?;
+        Ok((file, builder.size()))
+      })
+      .and_then(|(file, size)| Ok((file, size, archive_path(session, *node_id, &mut paths)?)));
+
+    match opened
+    {
+      Ok((mut file, size, path)) =>
+      {
+        let mut data = SizedReader::new(&mut file, size);
+        //a failure here is a write error that leave the archive in an unknown state so the export is aborted
+        archive.add(&path, size, &mut data)?;
+        entry.error = data.error();
+        entry.path = Some(path);
+        entry.size = Some(size);
+      },
+      Err(err) => entry.error = Some(err),
+    }
+    manifest.push(entry);
+  }
+
+  let manifest = serde_json::to_vec_pretty(&manifest).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+  archive.add(MANIFEST_NAME, manifest.len() as u64, &mut manifest.as_slice())?;
+  archive.finish()
+}
+
+/// Write the archive of `nodes_id` data in a blocking thread and return the stream to send to the client.
+/// The archive contain the nodes data under their tree path and a manifest of the exported nodes.
+pub fn export_nodes(session : ArcSession, nodes_id : Vec<TreeNodeId>, format : ArchiveFormat) -> AsyncVFile
+{
+  let (sender, receiver) = mpsc::sync_channel(PIPE_CAPACITY);
+  let errors = sender.clone();
+  let writer = PipeWriter{ sender, buffer : Vec::with_capacity(PIPE_CHUNK_SIZE) };
+  let reader = PipeReader{ receiver : Mutex::new(receiver), current : Cursor::new(Vec::new()) };
+
+  rocket::tokio::task::spawn_blocking(move ||
+  {
+    let archive : Box<dyn Archive> = match format
+    {
+      ArchiveFormat::Zip => Box::new(ZipArchive(ZipWriter::new_stream(writer))),
+      ArchiveFormat::Tar => Box::new(TarArchive(tar::Builder::new(writer))),
+    };
+
+    if let Err(err) = write_archive(&session, &nodes_id, archive)
+    {
+      warn!("Export failed : {}", err);
+      //let the client know the archive is not complete
+      let _ = errors.send(Err(err));
+    }
+  });
+
+  AsyncVFile::with_content(Box::new(reader), Content::Attachment{ name : format.file_name().into() })
+}
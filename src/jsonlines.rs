@@ -0,0 +1,67 @@
+//! Append only file of JSON lines, used by the audit log and the task history.
+
+use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+
+use log::warn;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+pub struct JsonLines
+{
+  file_path : PathBuf,
+  file : Mutex<File>,
+}
+
+impl JsonLines
+{
+  /// Open or create the file, existing lines are kept.
+  pub fn open<P : AsRef<Path>>(file_path : P) -> io::Result<Self>
+  {
+    let file_path = file_path.as_ref().to_path_buf();
+    let file = OpenOptions::new().create(true).append(true).open(&file_path)?;
+
+    Ok(JsonLines{ file_path, file : Mutex::new(file) })
+  }
+
+  pub fn path(&self) -> &Path
+  {
+    &self.file_path
+  }
+
+  /// Append `entry` as one line.
+  pub fn append<T : Serialize>(&self, entry : &T) -> io::Result<()>
+  {
+    let mut line = serde_json::to_vec(entry).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    line.push(b'\n');
+
+    let mut file = self.file.lock().unwrap();
+    file.write_all(&line)?;
+    file.flush()
+  }
+
+  /// Return the entries for which `filter` return true, in the order they were appended. Invalid lines are skipped.
+  pub fn read<T, F>(&self, filter : F) -> io::Result<Vec<T>>
+    where T : DeserializeOwned,
+          F : Fn(&T) -> bool,
+  {
+    //take the lock so we don't read a line being written
+    let _lock = self.file.lock().unwrap();
+    let reader = BufReader::new(File::open(&self.file_path)?);
+
+    let mut entries = Vec::new();
+    for line in reader.lines()
+    {
+      let line = line?;
+      match serde_json::from_str::<T>(&line)
+      {
+        Ok(entry) if filter(&entry) => entries.push(entry),
+        Ok(_) => (),
+        Err(err) => warn!("Invalid line in {} : {}", self.file_path.display(), err),
+      }
+    }
+    Ok(entries)
+  }
+}
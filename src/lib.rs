@@ -1,6 +1,26 @@
 #[macro_use] extern crate rocket;
 
 pub mod server;
+pub mod error;
 pub mod asyncvfile;
+pub mod range;
+pub mod auth;
+pub mod audit;
+pub mod jsonlines;
+pub mod tls;
+pub mod sandbox;
+pub mod upload;
+pub mod uploadsession;
+pub mod export;
+pub mod taskqueue;
+pub mod events;
+pub mod pipeline;
+pub mod openapi;
+pub mod page;
+pub mod nodevalue;
+pub mod named;
+pub mod savedquery;
+pub mod querycache;
+pub mod aggregate;
 #[cfg(feature = "frontend")]
 pub mod staticfileserver;
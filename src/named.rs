@@ -0,0 +1,136 @@
+//! Lists of items identified by a unique name, used by the pipelines and the saved queries.
+
+use std::io;
+
+/// Item stored in a list where it's name is unique.
+pub trait Named
+{
+  /// Kind of the item, used in the error messages.
+  const KIND : &'static str;
+
+  fn name(&self) -> &str;
+
+  /// Built-in items can't be replaced or removed.
+  fn is_builtin(&self) -> bool
+  {
+    false
+  }
+}
+
+pub fn not_found<T : Named>(name : &str) -> io::Error
+{
+  io::Error::new(io::ErrorKind::NotFound, format!("{} {} not found", T::KIND, name))
+}
+
+pub fn already_exists<T : Named>(name : &str) -> io::Error
+{
+  io::Error::new(io::ErrorKind::AlreadyExists, format!("{} {} already exists", T::KIND, name))
+}
+
+fn builtin<T : Named>(name : &str) -> io::Error
+{
+  io::Error::new(io::ErrorKind::PermissionDenied, format!("{} {} is built-in", T::KIND, name))
+}
+
+/// Return the item named `name`.
+pub fn get<'a, T : Named>(items : &'a [T], name : &str) -> io::Result<&'a T>
+{
+  items.iter().find(|item| item.name() == name).ok_or_else(|| not_found::<T>(name))
+}
+
+/// Add `item` at the end of `items`, fail if an item has the same name.
+pub fn add<T : Named>(items : &mut Vec<T>, item : T) -> io::Result<()>
+{
+  if items.iter().any(|current| current.name() == item.name())
+  {
+    return Err(already_exists::<T>(item.name()))
+  }
+  items.push(item);
+  Ok(())
+}
+
+/// Replace the item named `name` by `item`, that can be renamed if the new name isn't used by another item.
+pub fn replace<T : Named>(items : &mut [T], name : &str, item : T) -> io::Result<()>
+{
+  if item.name() != name && items.iter().any(|current| current.name() == item.name())
+  {
+    return Err(already_exists::<T>(item.name()))
+  }
+  let current = items.iter_mut().find(|current| current.name() == name).ok_or_else(|| not_found::<T>(name))?;
+  if current.is_builtin()
+  {
+    return Err(builtin::<T>(name))
+  }
+  *current = item;
+  Ok(())
+}
+
+/// Remove and return the item named `name`.
+pub fn remove<T : Named>(items : &mut Vec<T>, name : &str) -> io::Result<T>
+{
+  let index = items.iter().position(|item| item.name() == name).ok_or_else(|| not_found::<T>(name))?;
+  if items[index].is_builtin()
+  {
+    return Err(builtin::<T>(name))
+  }
+  Ok(items.remove(index))
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[derive(Debug, PartialEq)]
+  struct Item(&'static str, bool);
+
+  impl Named for Item
+  {
+    const KIND : &'static str = "Item";
+
+    fn name(&self) -> &str
+    {
+      self.0
+    }
+
+    fn is_builtin(&self) -> bool
+    {
+      self.1
+    }
+  }
+
+  fn kind<T>(result : io::Result<T>) -> Option<io::ErrorKind>
+  {
+    result.err().map(|err| err.kind())
+  }
+
+  #[test]
+  fn names_are_unique()
+  {
+    let mut items = vec![Item("a", false)];
+    assert_eq!(kind(add(&mut items, Item("a", false))), Some(io::ErrorKind::AlreadyExists));
+    add(&mut items, Item("b", false)).unwrap();
+    assert_eq!(kind(replace(&mut items, "a", Item("b", false))), Some(io::ErrorKind::AlreadyExists));
+    replace(&mut items, "a", Item("c", false)).unwrap();
+    assert_eq!(items, vec![Item("c", false), Item("b", false)]);
+  }
+
+  #[test]
+  fn missing_items_are_not_found()
+  {
+    let mut items = vec![Item("a", false)];
+    assert_eq!(kind(get(&items, "b")), Some(io::ErrorKind::NotFound));
+    assert_eq!(kind(replace(&mut items, "b", Item("b", false))), Some(io::ErrorKind::NotFound));
+    assert_eq!(kind(remove(&mut items, "b")), Some(io::ErrorKind::NotFound));
+    assert_eq!(remove(&mut items, "a").unwrap(), Item("a", false));
+  }
+
+  #[test]
+  fn builtin_items_cant_be_changed()
+  {
+    let mut items = vec![Item("a", true)];
+    assert_eq!(kind(replace(&mut items, "a", Item("a", false))), Some(io::ErrorKind::PermissionDenied));
+    assert_eq!(kind(remove(&mut items, "a")), Some(io::ErrorKind::PermissionDenied));
+    assert_eq!(items, vec![Item("a", true)]);
+  }
+}
@@ -0,0 +1,20 @@
+//! Value of an attribute of a node, used to sort and aggregate the nodes returned by a query.
+
+use serde_json::{json, Value};
+
+use tap::session::Session;
+use tap::tree::TreeNodeId;
+
+/// Return the value of an attribute of a node, or of the `name`, `extension` and `size` (size of the data) pseudo attributes.
+pub fn node_value(session : &Session, node_id : TreeNodeId, attribute : &str) -> Option<Value>
+{
+  let node = session.tree.get_node_from_id(node_id)?;
+
+  match attribute
+  {
+    "name" => Some(json!(node.name())),
+    "extension" => node.name().rsplit_once('.').filter(|(stem, _)| !stem.is_empty()).map(|(_, extension)| json!(extension.to_lowercase())),
+    "size" => node.value().get_value("data").map(|data| json!(data.as_vfile_builder().size())),
+    attribute => node.value().get_value(attribute).map(|value| json!(value)).filter(|value| !value.is_null()),
+  }
+}
@@ -0,0 +1,215 @@
+//! OpenAPI document generated from the routes mounted on the server
+//! and from the JSON schema of their request and response types.
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use serde_json::{json, Map, Value};
+
+use rocket::Route;
+
+use crate::error::ApiError;
+
+/// Schema of a `TreeNodeId`, as it's serialized by tap.
+#[derive(JsonSchema)]
+#[schemars(rename = "TreeNodeId")]
+pub struct NodeId
+{
+  pub index1 : usize,
+  pub stamp : usize,
+}
+
+/// Body of a request or of a response.
+pub enum Body
+{
+  Empty,
+  Json(Value),
+  Binary,
+  Text,
+  Html,
+  EventStream,
+}
+
+impl Body
+{
+  pub fn json<T : JsonSchema>(gen : &mut SchemaGenerator) -> Self
+  {
+    Body::Json(json!(gen.subschema_for::<T>()))
+  }
+
+  /// JSON body that's either a `A` or a `B`.
+  pub fn one_of<A : JsonSchema, B : JsonSchema>(gen : &mut SchemaGenerator) -> Self
+  {
+    Body::Json(json!({"oneOf" : [gen.subschema_for::<A>(), gen.subschema_for::<B>()]}))
+  }
+
+  fn content(&self) -> Option<Value>
+  {
+    match self
+    {
+      Body::Empty => None,
+      Body::Json(schema) => Some(json!({"application/json" : {"schema" : schema}})),
+      Body::Binary => Some(json!({"application/octet-stream" : {"schema" : {"type" : "string", "format" : "binary"}}})),
+      Body::Text => Some(json!({"text/plain" : {"schema" : {"type" : "string"}}})),
+      Body::Html => Some(json!({"text/html" : {"schema" : {"type" : "string"}}})),
+      Body::EventStream => Some(json!({"text/event-stream" : {"schema" : {"type" : "string"}}})),
+    }
+  }
+}
+
+/// Schema of a path or query parameter.
+pub struct Parameter
+{
+  schema : Value,
+  required : bool,
+  /// Structure sent as `name[field]=value`.
+  object : bool,
+}
+
+impl Parameter
+{
+  pub fn required<T : JsonSchema>(gen : &mut SchemaGenerator) -> Self
+  {
+    Parameter{ schema : json!(gen.subschema_for::<T>()), required : true, object : false }
+  }
+
+  pub fn optional<T : JsonSchema>(gen : &mut SchemaGenerator) -> Self
+  {
+    Parameter{ schema : json!(gen.subschema_for::<T>()), required : false, object : false }
+  }
+
+  /// Required structure, with each field sent as a query parameter.
+  pub fn object<T : JsonSchema>(gen : &mut SchemaGenerator) -> Self
+  {
+    Parameter{ schema : json!(gen.subschema_for::<T>()), required : true, object : true }
+  }
+}
+
+/// Describe the routes for the OpenAPI document.
+pub trait Describe
+{
+  /// Return the request and response body of a route from it's name.
+  fn bodies(&self, route : &str, gen : &mut SchemaGenerator) -> Option<(Body, Body)>;
+  /// Return the schema of a path or query parameter of a route.
+  fn parameter(&self, route : &str, name : &str, gen : &mut SchemaGenerator) -> Option<Parameter>;
+}
+
+/// OpenAPI document served by the server.
+pub struct ApiDocument(pub Value);
+
+/// Return the name of a dynamic segment like `<name>` or `<path..>`.
+fn dynamic_name(segment : &str) -> Option<&str>
+{
+  let name = segment.strip_prefix('<')?.strip_suffix('>')?;
+  Some(name.trim_end_matches(".."))
+}
+
+/// Return the OpenAPI description of the parameter `name` of a route.
+fn parameter<D : Describe>(describe : &D, route : &str, name : &str, location : &str, gen : &mut SchemaGenerator) -> Result<Value, String>
+{
+  let parameter = describe.parameter(route, name, gen).ok_or_else(|| format!("Parameter {} of route {} has no schema for the OpenAPI document", name, route))?;
+  let mut value = json!({"name" : name, "in" : location, "required" : parameter.required || location == "path", "schema" : parameter.schema});
+  if parameter.object
+  {
+    value["style"] = json!("deepObject");
+    value["explode"] = json!(true);
+  }
+  Ok(value)
+}
+
+/// Convert a route uri to an OpenAPI path and return it with the path and query parameters.
+fn path_parameters<D : Describe>(route : &Route, name : &str, describe : &D, gen : &mut SchemaGenerator) -> Result<(String, Vec<Value>), String>
+{
+  let mut parameters = Vec::new();
+
+  let mut segments = Vec::new();
+  for segment in route.uri.path().to_string().split('/')
+  {
+    match dynamic_name(segment)
+    {
+      Some(parameter_name) =>
+      {
+        parameters.push(parameter(describe, name, parameter_name, "path", gen)?);
+        segments.push(format!("{{{}}}", parameter_name));
+      },
+      None => segments.push(segment.to_string()),
+    }
+  }
+
+  if let Some(query) = route.uri.query()
+  {
+    let query = query.to_string();
+    for parameter_name in query.split('&').filter_map(dynamic_name)
+    {
+      parameters.push(parameter(describe, name, parameter_name, "query", gen)?);
+    }
+  }
+
+  Ok((segments.join("/"), parameters))
+}
+
+/// Generate the OpenAPI document of the routes mounted under `base`, described by `describe`.
+/// Return an error if a route or one of it's parameters is not described, so a new route can't be added without it's schemas.
+pub fn document<'a, R, D>(routes : R, base : &str, describe : &D) -> Result<ApiDocument, String>
+  where R : Iterator<Item = &'a Route>,
+        D : Describe,
+{
+  let mut gen = SchemaSettings::openapi3().into_generator();
+  let error = Body::json::<ApiError>(&mut gen).content();
+  let mut paths : BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+
+  for route in routes.filter(|route| route.uri.base().to_string() == base)
+  {
+    let name = route.name.as_deref().unwrap_or_default();
+    let (request, response) = describe.bodies(name, &mut gen).ok_or_else(|| format!("Route {} {} has no request and response bodies for the OpenAPI document", name, route.uri))?;
+    let (path, parameters) = path_parameters(route, name, describe, &mut gen)?;
+
+    let success = match response.content()
+    {
+      Some(content) => json!({"description" : "Success", "content" : content}),
+      None => json!({"description" : "Success"}),
+    };
+    let mut operation = json!({"operationId" : name, "parameters" : parameters,
+                               "responses" : {"200" : success, "default" : {"description" : "Error", "content" : error}}});
+    if let Some(content) = request.content()
+    {
+      operation["requestBody"] = json!({"required" : true, "content" : content});
+    }
+
+    paths.entry(path).or_default().insert(route.method.as_str().to_lowercase(), operation);
+  }
+
+  Ok(ApiDocument(json!({
+    "openapi" : "3.0.3",
+    "info" : {"title" : "TAPIR", "description" : env!("CARGO_PKG_DESCRIPTION"), "version" : env!("CARGO_PKG_VERSION")},
+    "paths" : paths,
+    "components" : {"schemas" : gen.definitions(),
+                    "securitySchemes" : {"api_key" : {"type" : "apiKey", "in" : "header", "name" : "x-api-key"}}},
+    "security" : [{"api_key" : []}],
+  })))
+}
+
+/// Swagger UI files, embedded from the `swagger-ui-dist` package directory set in `TAPIR_SWAGGER_UI_PATH` at build time,
+/// so the page doesn't load anything from outside of the server.
+#[cfg(feature = "api-docs")]
+pub static SWAGGER_UI_DIR : include_dir::Dir = include_dir::include_dir!("$TAPIR_SWAGGER_UI_PATH");
+
+/// Page displaying the OpenAPI document with Swagger UI.
+#[cfg(feature = "api-docs")]
+pub const SWAGGER_UI : &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <meta charset="utf-8"/>
+    <title>TAPIR API</title>
+    <link rel="stylesheet" href="/api/docs/swagger-ui.css"/>
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script src="/api/docs/swagger-ui-bundle.js"></script>
+    <script>
+      window.ui = SwaggerUIBundle({ url : "/api/openapi.json", dom_id : "#swagger-ui" });
+    </script>
+  </body>
+</html>
+"#;
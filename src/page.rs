@@ -0,0 +1,395 @@
+//! Sorting and pagination of the nodes returned by a query.
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use serde::ser::{SerializeStruct, Serializer};
+use schemars::JsonSchema;
+use serde_json::{json, Value};
+
+use tap::session::Session;
+use tap::tree::TreeNodeId;
+
+use crate::error::ApiError;
+use crate::nodevalue::node_value;
+
+/// Sort order of the results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Order
+{
+  #[default]
+  Asc,
+  Desc,
+}
+
+/// Part of the results requested by the client, the results are returned unchanged if none of the fields are set.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+pub struct PageRequest
+{
+  /// Maximum number of results returned.
+  pub limit : Option<usize>,
+  pub offset : Option<usize>,
+  /// `next_cursor` returned with the previous page, replace `offset`.
+  pub cursor : Option<String>,
+  /// `name`, `extension`, `size` or the path of an attribute.
+  pub sort : Option<String>,
+  #[serde(default)]
+  pub order : Order,
+}
+
+/// A page of results with the total number of results.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Page<T>
+{
+  pub total : usize,
+  pub offset : usize,
+  pub items : Vec<T>,
+  /// Cursor of the next page, none if this is the last page.
+  pub next_cursor : Option<String>,
+}
+
+/// All the results when no page is requested, or a page of the results.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Paged<T>
+{
+  All(Vec<T>),
+  Page(Page<T>),
+}
+
+impl<T> Paged<T>
+{
+  /// Serialize the results like `Paged` would, with the items serialized by the value returned by `items`.
+  pub fn serialize_with<'a, S, I, F>(&'a self, serializer : S, items : F) -> Result<S::Ok, S::Error>
+    where S : Serializer,
+          I : Serialize,
+          F : FnOnce(&'a [T]) -> I,
+  {
+    match self
+    {
+      Paged::All(all) => items(all).serialize(serializer),
+      Paged::Page(page) =>
+      {
+        let mut state = serializer.serialize_struct("Page", 4)?;
+        state.serialize_field("total", &page.total)?;
+        state.serialize_field("offset", &page.offset)?;
+        state.serialize_field("items", &items(&page.items))?;
+        state.serialize_field("next_cursor", &page.next_cursor)?;
+        state.end()
+      },
+    }
+  }
+}
+
+/// Value a node is sorted by, nodes without the value are always sorted last.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum SortKey
+{
+  Number(f64),
+  Text(String),
+  Missing,
+}
+
+impl SortKey
+{
+  fn from_json(value : Value) -> Self
+  {
+    match value
+    {
+      Value::Number(number) => number.as_f64().map(SortKey::Number).unwrap_or(SortKey::Missing),
+      Value::Bool(value) => SortKey::Number(value as u8 as f64),
+      //dates are serialized in rfc3339 so they are sorted chronologically as text
+      Value::String(value) => SortKey::Text(value),
+      Value::Null => SortKey::Missing,
+      value => SortKey::Text(value.to_string()),
+    }
+  }
+
+  fn compare(&self, other : &SortKey, order : Order) -> Ordering
+  {
+    let ordering = match (self, other)
+    {
+      (SortKey::Missing, SortKey::Missing) => return Ordering::Equal,
+      (SortKey::Missing, _) => return Ordering::Greater,
+      (_, SortKey::Missing) => return Ordering::Less,
+      (SortKey::Number(a), SortKey::Number(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+      (SortKey::Text(a), SortKey::Text(b)) => a.cmp(b),
+      (SortKey::Number(_), SortKey::Text(_)) => Ordering::Less,
+      (SortKey::Text(_), SortKey::Number(_)) => Ordering::Greater,
+    };
+
+    match order
+    {
+      Order::Asc => ordering,
+      Order::Desc => ordering.reverse(),
+    }
+  }
+}
+
+/// Return the value `node_id` is sorted by.
+fn sort_key(session : &Session, node_id : TreeNodeId, sort : &str) -> SortKey
+{
+  node_value(session, node_id, sort).map_or(SortKey::Missing, SortKey::from_json)
+}
+
+/// Return the place of a node in the tree, used to order the nodes with the same sort value.
+fn node_place(node_id : TreeNodeId) -> (u64, u64)
+{
+  let node_id = json!(node_id);
+  (node_id["index1"].as_u64().unwrap_or(0), node_id["stamp"].as_u64().unwrap_or(0))
+}
+
+/// A node with the value it's sorted by.
+struct Entry
+{
+  key : SortKey,
+  place : (u64, u64),
+  node_id : TreeNodeId,
+}
+
+impl Entry
+{
+  fn new(key : SortKey, node_id : TreeNodeId) -> Self
+  {
+    Entry{ key, place : node_place(node_id), node_id }
+  }
+
+  /// Nodes with the same value are ordered by their place in the tree, so the order is the same for every page.
+  fn compare(&self, key : &SortKey, place : (u64, u64), order : Order) -> Ordering
+  {
+    self.key.compare(key, order).then(self.place.cmp(&place))
+  }
+}
+
+/// Last node of a page, the next page start after this node even if nodes were added or removed before it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Cursor
+{
+  /// Used if the node can't be found anymore in unsorted results.
+  offset : usize,
+  place : (u64, u64),
+  /// Value the node was sorted by.
+  key : Option<SortKey>,
+}
+
+fn encode_cursor(cursor : &Cursor) -> String
+{
+  serde_json::to_vec(cursor).unwrap_or_default().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_cursor(cursor : &str) -> Result<Cursor, ApiError>
+{
+  let invalid = || ApiError::bad_request("invalid_cursor", format!("Invalid cursor {}", cursor));
+
+  if cursor.len() % 2 != 0 || !cursor.is_ascii()
+  {
+    return Err(invalid())
+  }
+  let bytes = (0..cursor.len()).step_by(2).map(|index| u8::from_str_radix(&cursor[index..index + 2], 16))
+                                .collect::<Result<Vec<u8>, _>>().map_err(|_| invalid())?;
+  serde_json::from_slice(&bytes).map_err(|_| invalid())
+}
+
+/// Nodes in the order they are returned by the pages, kept in the query cache so they are not sorted again for each page.
+pub struct SortedNodes
+{
+  entries : Vec<Entry>,
+}
+
+impl PageRequest
+{
+  pub fn is_set(&self) -> bool
+  {
+    self.limit.is_some() || self.offset.is_some() || self.cursor.is_some() || self.sort.is_some()
+  }
+
+  /// Return the nodes sorted by the requested value, or in the order of `nodes_id` if no sort is requested.
+  pub fn sort_nodes(&self, session : &Session, nodes_id : &[TreeNodeId]) -> SortedNodes
+  {
+    let entries = nodes_id.iter().map(|node_id| match &self.sort
+    {
+      Some(sort) => Entry::new(sort_key(session, *node_id, sort), *node_id),
+      None => Entry::new(SortKey::Missing, *node_id),
+    }).collect();
+    self.sort_entries(entries)
+  }
+
+  fn sort_entries(&self, mut entries : Vec<Entry>) -> SortedNodes
+  {
+    if self.sort.is_some()
+    {
+      entries.sort_by(|a, b| a.compare(&b.key, b.place, self.order));
+    }
+    SortedNodes{ entries }
+  }
+
+  /// Sort the nodes, then return the requested page.
+  pub fn apply(&self, session : &Session, nodes_id : &[TreeNodeId]) -> Result<Paged<TreeNodeId>, ApiError>
+  {
+    if !self.is_set()
+    {
+      return Ok(Paged::All(nodes_id.to_vec()))
+    }
+    self.page(&self.sort_nodes(session, nodes_id))
+  }
+
+  /// Return the page of `nodes` starting at the offset or after the cursor, `nodes` must be sorted by `sort_nodes` with the same sort and order.
+  pub fn page(&self, nodes : &SortedNodes) -> Result<Paged<TreeNodeId>, ApiError>
+  {
+    let entries = &nodes.entries;
+    let offset = match &self.cursor
+    {
+      Some(cursor) =>
+      {
+        let cursor = decode_cursor(cursor)?;
+        match (&self.sort, &cursor.key)
+        {
+          (Some(_), Some(key)) => entries.partition_point(|entry| entry.compare(key, cursor.place, self.order) != Ordering::Greater),
+          _ => entries.iter().position(|entry| entry.place == cursor.place).map_or(cursor.offset, |index| index + 1),
+        }
+      },
+      None => self.offset.unwrap_or(0),
+    };
+
+    let total = entries.len();
+    let end = offset.saturating_add(self.limit.unwrap_or(usize::MAX)).min(total);
+    let page = entries.get(offset..end).unwrap_or_default();
+    let next_cursor = match page.last()
+    {
+      Some(last) if end < total =>
+      {
+        let key = self.sort.as_ref().map(|_| last.key.clone());
+        Some(encode_cursor(&Cursor{ offset : end, place : last.place, key }))
+      },
+      _ => None,
+    };
+    let items = page.iter().map(|entry| entry.node_id).collect();
+
+    Ok(Paged::Page(Page{ total, offset, items, next_cursor }))
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn node_id(index : u64) -> TreeNodeId
+  {
+    serde_json::from_value(json!({"index1" : index, "stamp" : 0})).unwrap()
+  }
+
+  fn entries(sizes : &[Option<f64>]) -> Vec<Entry>
+  {
+    sizes.iter().enumerate().map(|(index, size)| Entry::new(size.map_or(SortKey::Missing, SortKey::Number), node_id(index as u64 + 1))).collect()
+  }
+
+  fn sorted(sort : bool, order : Order) -> PageRequest
+  {
+    PageRequest{ limit : Some(2), sort : sort.then(|| "size".to_string()), order, ..Default::default() }
+  }
+
+  fn page(request : &PageRequest, entries : Vec<Entry>) -> Result<Paged<TreeNodeId>, ApiError>
+  {
+    request.page(&request.sort_entries(entries))
+  }
+
+  fn items(paged : Paged<TreeNodeId>) -> (Vec<u64>, Option<String>)
+  {
+    match paged
+    {
+      Paged::Page(page) => (page.items.into_iter().map(|node_id| node_place(node_id).0).collect(), page.next_cursor),
+      Paged::All(_) => panic!("expected a page"),
+    }
+  }
+
+  #[test]
+  fn compare_sorts_missing_last()
+  {
+    let number = SortKey::Number(1.0);
+    let text = SortKey::Text("a".to_string());
+
+    assert_eq!(number.compare(&SortKey::Number(2.0), Order::Asc), Ordering::Less);
+    assert_eq!(number.compare(&SortKey::Number(2.0), Order::Desc), Ordering::Greater);
+    assert_eq!(number.compare(&text, Order::Asc), Ordering::Less);
+    assert_eq!(SortKey::Missing.compare(&number, Order::Asc), Ordering::Greater);
+    assert_eq!(SortKey::Missing.compare(&number, Order::Desc), Ordering::Greater);
+    assert_eq!(text.compare(&SortKey::Missing, Order::Desc), Ordering::Less);
+  }
+
+  #[test]
+  fn from_json_converts_values()
+  {
+    assert_eq!(SortKey::from_json(json!(3)), SortKey::Number(3.0));
+    assert_eq!(SortKey::from_json(json!(true)), SortKey::Number(1.0));
+    assert_eq!(SortKey::from_json(json!("a")), SortKey::Text("a".to_string()));
+    assert_eq!(SortKey::from_json(Value::Null), SortKey::Missing);
+  }
+
+  #[test]
+  fn page_sorts_and_follows_cursor()
+  {
+    let request = sorted(true, Order::Desc);
+    let (first, cursor) = items(page(&request, entries(&[Some(1.0), None, Some(3.0), Some(2.0), Some(3.0)])).unwrap());
+    assert_eq!(first, vec![3, 5]);
+
+    let request = PageRequest{ cursor, ..sorted(true, Order::Desc) };
+    let (second, cursor) = items(page(&request, entries(&[Some(1.0), None, Some(3.0), Some(2.0), Some(3.0)])).unwrap());
+    assert_eq!(second, vec![4, 1]);
+
+    let request = PageRequest{ cursor, ..sorted(true, Order::Desc) };
+    let (last, cursor) = items(page(&request, entries(&[Some(1.0), None, Some(3.0), Some(2.0), Some(3.0)])).unwrap());
+    assert_eq!(last, vec![2]);
+    assert_eq!(cursor, None);
+  }
+
+  #[test]
+  fn cursor_doesnt_drift_when_nodes_are_added()
+  {
+    let (_, cursor) = items(page(&sorted(true, Order::Asc), entries(&[Some(1.0), Some(2.0), Some(3.0), Some(4.0)])).unwrap());
+
+    //a node sorted before the cursor is added between the two pages
+    let request = PageRequest{ cursor, ..sorted(true, Order::Asc) };
+    let (next, _) = items(page(&request, entries(&[Some(1.0), Some(2.0), Some(3.0), Some(4.0), Some(0.0)])).unwrap());
+    assert_eq!(next, vec![3, 4]);
+  }
+
+  #[test]
+  fn unsorted_cursor_resumes_after_last_node()
+  {
+    let (first, cursor) = items(page(&sorted(false, Order::Asc), entries(&[None, None, None, None])).unwrap());
+    assert_eq!(first, vec![1, 2]);
+
+    //the first node is removed between the two pages
+    let mut remaining = entries(&[None, None, None, None]);
+    remaining.remove(0);
+    let request = PageRequest{ cursor, ..sorted(false, Order::Asc) };
+    let (next, _) = items(page(&request, remaining).unwrap());
+    assert_eq!(next, vec![3, 4]);
+  }
+
+  #[test]
+  fn offset_past_the_end_is_empty()
+  {
+    let request = PageRequest{ offset : Some(10), ..sorted(false, Order::Asc) };
+    assert_eq!(items(page(&request, entries(&[None, None])).unwrap()), (vec![], None));
+  }
+
+  #[test]
+  fn invalid_cursor_is_rejected()
+  {
+    for cursor in ["0", "zz", "7b7d", "é"]
+    {
+      let request = PageRequest{ cursor : Some(cursor.to_string()), ..sorted(true, Order::Asc) };
+      assert_eq!(page(&request, entries(&[None])).unwrap_err().code, "invalid_cursor");
+    }
+  }
+
+  #[test]
+  fn cursor_round_trips()
+  {
+    let cursor = Cursor{ offset : 2, place : (7, 1), key : Some(SortKey::Text("b".to_string())) };
+    assert_eq!(decode_cursor(&encode_cursor(&cursor)).unwrap(), cursor);
+  }
+}
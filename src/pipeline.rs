@@ -0,0 +1,186 @@
+//! Pipelines automatically schedule plugins on the nodes found by a query
+//! when a task of another plugin finish, to fully process an image without manual scheduling.
+
+use std::io;
+use std::thread;
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::HashSet;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use serde_json::Value;
+
+use tap::tree::TreeNodeId;
+use ::tap_query::filter::Filter;
+
+use crate::server::ArcSession;
+use crate::named::{self, Named};
+use crate::sandbox::{EvidenceRoots, only_takes_paths};
+use crate::taskqueue::{QueuedTask, TaskQueue, TaskStatus, TaskOrigin};
+
+/// String replaced by the node id in the arguments template.
+pub const NODE_ID_PLACEHOLDER : &str = "{node_id}";
+
+/// When a task of the `after` plugin finish, schedule `plugin` on each node returned by `query`
+/// executed on the node the task was applied to.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PipelineRule
+{
+  pub name : String,
+  pub after : String,
+  pub query : String,
+  pub plugin : String,
+  /// Arguments of the plugin, where the `{node_id}` strings are replaced by the id of the node.
+  pub arguments : Value,
+  #[serde(default)]
+  pub priority : i32,
+}
+
+impl Named for PipelineRule
+{
+  const KIND : &'static str = "Pipeline";
+
+  fn name(&self) -> &str
+  {
+    &self.name
+  }
+}
+
+/// Replace the `{node_id}` strings of `template` by `node_id`.
+pub fn apply_template(template : &Value, node_id : TreeNodeId) -> Value
+{
+  match template
+  {
+    Value::String(value) if value == NODE_ID_PLACEHOLDER => serde_json::json!(node_id),
+    Value::Array(values) => Value::Array(values.iter().map(|value| apply_template(value, node_id)).collect()),
+    Value::Object(map) => Value::Object(map.iter().map(|(key, value)| (key.clone(), apply_template(value, node_id))).collect()),
+    value => value.clone(),
+  }
+}
+
+pub struct Pipelines
+{
+  session : ArcSession,
+  task_queue : Arc<TaskQueue>,
+  evidence_roots : Arc<EvidenceRoots>,
+  rules : RwLock<Vec<PipelineRule>>,
+  /// Plugin and node already scheduled by a pipeline, to never process a node twice.
+  scheduled : Mutex<HashSet<(String, String)>>,
+}
+
+impl Pipelines
+{
+  /// Check the rules and start the thread that apply them when tasks finish.
+  pub fn start(session : ArcSession, task_queue : Arc<TaskQueue>, evidence_roots : Arc<EvidenceRoots>, rules : Vec<PipelineRule>) -> io::Result<Arc<Pipelines>>
+  {
+    let pipelines = Arc::new(Pipelines{ session, task_queue, evidence_roots, rules : RwLock::new(Vec::new()), scheduled : Mutex::new(HashSet::new()) });
+    for rule in rules
+    {
+      pipelines.add(rule)?;
+    }
+
+    let finished = pipelines.task_queue.subscribe_finished();
+    let listener = pipelines.clone();
+    thread::spawn(move ||
+    {
+      for task in finished
+      {
+        listener.trigger(&task);
+      }
+    });
+
+    Ok(pipelines)
+  }
+
+  /// Check the rule and resolve the paths in its arguments.
+  fn check(&self, rule : &mut PipelineRule) -> io::Result<()>
+  {
+    let invalid = |message : String| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+
+    for plugin in [&rule.after, &rule.plugin]
+    {
+      if self.session.plugins_db.find(plugin).is_none()
+      {
+        return invalid(format!("Plugin {} not found", plugin))
+      }
+    }
+    //the node id placeholder would be checked as a path
+    if only_takes_paths(&rule.plugin)
+    {
+      return invalid(format!("Plugin {} can't be used in a pipeline", rule.plugin))
+    }
+    self.evidence_roots.check_value(&rule.plugin, &mut rule.arguments)?;
+    if rule.query.trim().is_empty()
+    {
+      return invalid("Query is empty".into())
+    }
+    Ok(())
+  }
+
+  pub fn rules(&self) -> Vec<PipelineRule>
+  {
+    self.rules.read().unwrap().clone()
+  }
+
+  pub fn add(&self, mut rule : PipelineRule) -> io::Result<()>
+  {
+    self.check(&mut rule)?;
+    let description = format!("Pipeline {} : {} after {} on {}", rule.name, rule.plugin, rule.after, rule.query);
+    named::add(&mut self.rules.write().unwrap(), rule)?;
+    info!("{}", description);
+    Ok(())
+  }
+
+  pub fn replace(&self, name : &str, mut rule : PipelineRule) -> io::Result<()>
+  {
+    self.check(&mut rule)?;
+    named::replace(&mut self.rules.write().unwrap(), name, rule)
+  }
+
+  pub fn remove(&self, name : &str) -> io::Result<()>
+  {
+    named::remove(&mut self.rules.write().unwrap(), name).map(|_| ())
+  }
+
+  /// Schedule the plugins of the rules matching a task that finished without error.
+  fn trigger(&self, task : &QueuedTask)
+  {
+    //tasks replayed by load or launched by a plugin were already processed
+    if task.origin != TaskOrigin::Queue || task.state != TaskStatus::Finished || task.error.is_some() || task.cancel_requested
+    {
+      return
+    }
+
+    let root = task.input.and_then(|node_id| self.session.tree.node_path(node_id)).unwrap_or_else(|| "/root".into());
+    let rules : Vec<PipelineRule> = self.rules().into_iter().filter(|rule| rule.after == task.plugin).collect();
+
+    for rule in rules
+    {
+      let nodes_id = match Filter::path(&self.session.tree, &rule.query, &root)
+      {
+        Ok(nodes_id) => nodes_id,
+        Err(err) =>
+        {
+          warn!("Pipeline {} query failed : {}", rule.name, err);
+          continue
+        },
+      };
+
+      for node_id in nodes_id
+      {
+        let key = (rule.plugin.clone(), serde_json::json!(node_id).to_string());
+        if !self.scheduled.lock().unwrap().insert(key)
+        {
+          continue
+        }
+
+        let arguments = apply_template(&rule.arguments, node_id).to_string();
+        if let Err(err) = self.task_queue.push(&rule.plugin, &arguments, false, rule.priority)
+        {
+          warn!("Pipeline {} can't schedule {} : {}", rule.name, rule.plugin, err);
+        }
+      }
+    }
+  }
+}
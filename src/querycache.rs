@@ -0,0 +1,197 @@
+//! Cache of the nodes matched by a query, so the tree isn't walked again while it's not modified.
+
+use std::thread;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::{HashMap, VecDeque};
+
+use log::info;
+use rocket::tokio::sync::broadcast::error::RecvError;
+
+use tap::tree::TreeNodeId;
+
+use crate::events::{Event, Events};
+use crate::taskqueue::{TaskQueue, TaskStatus};
+use crate::page::{Order, SortedNodes};
+
+/// Number of query results kept, the oldest result is dropped when a new one is added.
+const QUERY_CACHE_CAPACITY : usize = 64;
+
+type QueryKey = (String, String);
+
+/// Nodes matched by a query, and the same nodes sorted for each sort and order requested by the pages.
+struct QueryResult
+{
+  nodes_id : Arc<Vec<TreeNodeId>>,
+  sorted : HashMap<(String, Order), Arc<SortedNodes>>,
+}
+
+#[derive(Default)]
+struct CacheState
+{
+  /// Incremented each time the tree is modified, results computed before are not cached.
+  generation : u64,
+  /// Number of plugins run outside of the task queue.
+  updating : usize,
+  /// Number of tasks created on the task scheduler when the results were cached.
+  scheduler_tasks : usize,
+  results : HashMap<QueryKey, QueryResult>,
+  order : VecDeque<QueryKey>,
+}
+
+/// Nodes matched by a query on a root, cleared when nodes are added or removed or when attributes change.
+/// Results are not cached while plugins are running as they modify the tree at any time,
+/// this include the subtasks launched by the plugins directly on the task scheduler.
+pub struct QueryCache
+{
+  task_queue : Arc<TaskQueue>,
+  state : Mutex<CacheState>,
+}
+
+/// Plugin run outside of the task queue, the cache is disabled until it's dropped.
+pub struct UpdateGuard<'a>
+{
+  cache : &'a QueryCache,
+}
+
+impl Drop for UpdateGuard<'_>
+{
+  fn drop(&mut self)
+  {
+    let mut state = self.cache.lock();
+    state.updating -= 1;
+    QueryCache::clear(&mut state);
+  }
+}
+
+impl QueryCache
+{
+  pub fn new(task_queue : Arc<TaskQueue>) -> Arc<Self>
+  {
+    Arc::new(QueryCache{ task_queue, state : Mutex::new(CacheState::default()) })
+  }
+
+  fn lock(&self) -> MutexGuard<'_, CacheState>
+  {
+    self.state.lock().unwrap()
+  }
+
+  fn clear(state : &mut CacheState)
+  {
+    state.generation += 1;
+    state.results.clear();
+    state.order.clear();
+  }
+
+  /// Return true if the results can be cached, the results are dropped if tasks were created on the task scheduler since they were cached,
+  /// as a task could have modified the tree and finished before we receive it's events.
+  fn enabled(&self, state : &mut CacheState) -> bool
+  {
+    let scheduler_tasks = self.task_queue.scheduler_task_count();
+    if scheduler_tasks != state.scheduler_tasks
+    {
+      QueryCache::clear(state);
+      state.scheduler_tasks = scheduler_tasks;
+    }
+    state.updating == 0 && !self.task_queue.scheduler_active()
+  }
+
+  /// Drop all the results, must be called each time the tree is modified.
+  pub fn invalidate(&self)
+  {
+    QueryCache::clear(&mut self.lock());
+  }
+
+  /// Disable the cache while a plugin is run outside of the task queue, it's invalidated when the guard is dropped.
+  pub fn update(&self) -> UpdateGuard<'_>
+  {
+    let mut state = self.lock();
+    state.updating += 1;
+    QueryCache::clear(&mut state);
+    UpdateGuard{ cache : self }
+  }
+
+  /// Return the cached result of `query` on `root`, or the current generation to pass to `insert` once the query is executed.
+  pub fn get(&self, query : &str, root : &str) -> Result<Arc<Vec<TreeNodeId>>, u64>
+  {
+    let mut state = self.lock();
+    if self.enabled(&mut state)
+    {
+      if let Some(result) = state.results.get(&(query.to_string(), root.to_string()))
+      {
+        return Ok(result.nodes_id.clone())
+      }
+    }
+    Err(state.generation)
+  }
+
+  /// Keep the result of a query, unless the tree was modified since `generation`.
+  pub fn insert(&self, generation : u64, query : &str, root : &str, result : Arc<Vec<TreeNodeId>>)
+  {
+    let mut state = self.lock();
+    if !self.enabled(&mut state) || state.generation != generation
+    {
+      return
+    }
+
+    let key = (query.to_string(), root.to_string());
+    if state.results.insert(key.clone(), QueryResult{ nodes_id : result, sorted : HashMap::new() }).is_none()
+    {
+      state.order.push_back(key);
+    }
+    while state.order.len() > QUERY_CACHE_CAPACITY
+    {
+      if let Some(oldest) = state.order.pop_front()
+      {
+        state.results.remove(&oldest);
+      }
+    }
+  }
+
+  /// Return the sorted nodes of `query` on `root`, if `nodes_id` is still the cached result of the query.
+  pub fn get_sorted(&self, query : &str, root : &str, nodes_id : &Arc<Vec<TreeNodeId>>, sort : &str, order : Order) -> Option<Arc<SortedNodes>>
+  {
+    let mut state = self.lock();
+    if !self.enabled(&mut state)
+    {
+      return None
+    }
+    let result = state.results.get(&(query.to_string(), root.to_string())).filter(|result| Arc::ptr_eq(&result.nodes_id, nodes_id))?;
+    result.sorted.get(&(sort.to_string(), order)).cloned()
+  }
+
+  /// Keep the nodes of `query` on `root` sorted by `sort`, if `nodes_id` is still the cached result of the query.
+  pub fn insert_sorted(&self, query : &str, root : &str, nodes_id : &Arc<Vec<TreeNodeId>>, sort : &str, order : Order, sorted : Arc<SortedNodes>)
+  {
+    let mut state = self.lock();
+    if !self.enabled(&mut state)
+    {
+      return
+    }
+    if let Some(result) = state.results.get_mut(&(query.to_string(), root.to_string())).filter(|result| Arc::ptr_eq(&result.nodes_id, nodes_id))
+    {
+      result.sorted.insert((sort.to_string(), order), sorted);
+    }
+  }
+
+  /// Invalidate the cache when tasks are launched or finished and when the node count change.
+  pub fn watch(self : &Arc<Self>, events : &Events)
+  {
+    let cache = self.clone();
+    let mut receiver = events.subscribe();
+    thread::spawn(move ||
+    {
+      loop
+      {
+        match receiver.blocking_recv()
+        {
+          Ok(Event::Task{ task, .. }) if task.state == TaskStatus::Waiting => (),
+          Ok(_) => cache.invalidate(),
+          //events were missed, one of them may have modified the tree
+          Err(RecvError::Lagged(_)) => cache.invalidate(),
+          Err(RecvError::Closed) => break,
+        }
+      }
+      info!("Query cache stopped watching events");
+    });
+  }
+}
@@ -0,0 +1,174 @@
+//! Parsing of the `Range` and `If-Range` headers used to download part of a file.
+
+use std::convert::Infallible;
+
+use rocket::Request;
+use rocket::request::{Outcome, FromRequest};
+
+/// Maximum number of ranges accepted in one request, the whole file is sent if there is more.
+const MAX_RANGES : usize = 64;
+
+/// Inclusive byte ranges to send to the client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRanges
+{
+  /// Send the whole file.
+  Full,
+  Single(u64, u64),
+  Multiple(Vec<(u64, u64)>),
+  /// None of the requested ranges overlap the file.
+  Unsatisfiable,
+}
+
+/// `Range` and `If-Range` headers of a request.
+#[derive(Debug, Clone, Default)]
+pub struct RangeHeaders
+{
+  pub range : Option<String>,
+  pub if_range : Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RangeHeaders
+{
+  type Error = Infallible;
+
+  async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error>
+  {
+    Outcome::Success(RangeHeaders{ range : req.headers().get_one("Range").map(String::from),
+                                   if_range : req.headers().get_one("If-Range").map(String::from) })
+  }
+}
+
+impl RangeHeaders
+{
+  /// Return the ranges to send for a file of `size` bytes identified by `etag`.
+  /// Invalid headers are ignored and the whole file is sent, as required by RFC 7233.
+  pub fn ranges(&self, size : u64, etag : &str) -> ByteRanges
+  {
+    let range = match &self.range
+    {
+      Some(range) => range,
+      None => return ByteRanges::Full,
+    };
+
+    //the file could have changed since the client got the etag
+    if let Some(if_range) = &self.if_range
+    {
+      if if_range.trim() != etag
+      {
+        return ByteRanges::Full
+      }
+    }
+
+    let specs = match range.trim().strip_prefix("bytes=")
+    {
+      Some(specs) => specs,
+      None => return ByteRanges::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',')
+    {
+      let (start, end) = match spec.trim().split_once('-')
+      {
+        Some(spec) => spec,
+        None => return ByteRanges::Full,
+      };
+
+      let range = match (start.trim(), end.trim())
+      {
+        ("", suffix) => match suffix.parse::<u64>()
+        {
+          Ok(length) => (length > 0 && size > 0).then(|| (size.saturating_sub(length), size - 1)),
+          Err(_) => return ByteRanges::Full,
+        },
+        (start, "") => match start.parse::<u64>()
+        {
+          Ok(start) => (start < size).then(|| (start, size - 1)),
+          Err(_) => return ByteRanges::Full,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>())
+        {
+          (Ok(start), Ok(end)) if start <= end => (start < size).then(|| (start, end.min(size - 1))),
+          _ => return ByteRanges::Full,
+        },
+      };
+      ranges.extend(range);
+    }
+
+    match ranges.len()
+    {
+      0 => ByteRanges::Unsatisfiable,
+      1 => ByteRanges::Single(ranges[0].0, ranges[0].1),
+      count if count > MAX_RANGES => ByteRanges::Full,
+      _ => ByteRanges::Multiple(ranges),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn ranges(range : &str, if_range : Option<&str>) -> ByteRanges
+  {
+    RangeHeaders{ range : Some(range.to_string()), if_range : if_range.map(String::from) }.ranges(100, "\"etag\"")
+  }
+
+  #[test]
+  fn no_range_is_full()
+  {
+    assert_eq!(RangeHeaders::default().ranges(100, "\"etag\""), ByteRanges::Full);
+  }
+
+  #[test]
+  fn single_ranges()
+  {
+    assert_eq!(ranges("bytes=0-9", None), ByteRanges::Single(0, 9));
+    assert_eq!(ranges("bytes=90-", None), ByteRanges::Single(90, 99));
+    assert_eq!(ranges("bytes=-10", None), ByteRanges::Single(90, 99));
+    assert_eq!(ranges("bytes=-500", None), ByteRanges::Single(0, 99));
+    assert_eq!(ranges("bytes=50-500", None), ByteRanges::Single(50, 99));
+  }
+
+  #[test]
+  fn multiple_ranges()
+  {
+    assert_eq!(ranges("bytes=0-9, 20-29", None), ByteRanges::Multiple(vec![(0, 9), (20, 29)]));
+    //ranges outside of the file are ignored
+    assert_eq!(ranges("bytes=0-9,200-300", None), ByteRanges::Single(0, 9));
+  }
+
+  #[test]
+  fn unsatisfiable_ranges()
+  {
+    assert_eq!(ranges("bytes=100-", None), ByteRanges::Unsatisfiable);
+    assert_eq!(ranges("bytes=-0", None), ByteRanges::Unsatisfiable);
+    assert_eq!(RangeHeaders{ range : Some("bytes=0-".to_string()), if_range : None }.ranges(0, ""), ByteRanges::Unsatisfiable);
+  }
+
+  #[test]
+  fn invalid_ranges_are_full()
+  {
+    for range in ["items=0-9", "bytes=9-0", "bytes=a-9", "bytes=0-9,x", "bytes=10"]
+    {
+      assert_eq!(ranges(range, None), ByteRanges::Full, "{}", range);
+    }
+  }
+
+  #[test]
+  fn too_many_ranges_are_full()
+  {
+    let range = format!("bytes={}", (0..=MAX_RANGES).map(|index| format!("{}-{}", index, index)).collect::<Vec<_>>().join(","));
+    assert_eq!(RangeHeaders{ range : Some(range), if_range : None }.ranges(1000, ""), ByteRanges::Full);
+  }
+
+  #[test]
+  fn if_range_must_match_etag()
+  {
+    assert_eq!(ranges("bytes=0-9", Some("\"etag\"")), ByteRanges::Single(0, 9));
+    assert_eq!(ranges("bytes=0-9", Some("\"other\"")), ByteRanges::Full);
+  }
+}
@@ -0,0 +1,265 @@
+//! Restrict the files that plugins reading the server filesystem can access.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde_json::Value;
+
+/// Plugins that take paths on the server filesystem as arguments, with the name of the argument containing the path
+/// or `None` if every string in the arguments is a path.
+const FILESYSTEM_PLUGINS : &[(&str, Option<&str>)] = &[("local", None), ("device", None), ("yara", Some("rules_file"))];
+
+/// Return true if every string in the arguments of `plugin_name` is a path on the server filesystem.
+pub fn only_takes_paths(plugin_name : &str) -> bool
+{
+  FILESYSTEM_PLUGINS.iter().any(|(name, argument)| *name == plugin_name && argument.is_none())
+}
+
+/// Directories from which evidence can be loaded.
+pub struct EvidenceRoots
+{
+  roots : Vec<PathBuf>,
+  /// Directories inside the roots that can't be loaded.
+  excluded : Vec<PathBuf>,
+}
+
+impl EvidenceRoots
+{
+  /// Resolve `roots`, roots that can't be resolved are ignored.
+  pub fn new<P : AsRef<Path>>(roots : &[P]) -> Self
+  {
+    let roots = roots.iter().filter_map(|root|
+    {
+      let root = root.as_ref();
+      fs::canonicalize(root).map_err(|err| warn!("Ignoring evidence root {} : {}", root.display(), err)).ok()
+    }).collect();
+
+    EvidenceRoots{ roots, excluded : Vec::new() }
+  }
+
+  /// Forbid access to `dir` even if it's inside an evidence root.
+  pub fn exclude<P : AsRef<Path>>(mut self, dir : P) -> io::Result<Self>
+  {
+    self.excluded.push(fs::canonicalize(dir)?);
+    Ok(self)
+  }
+
+  pub fn roots(&self) -> &[PathBuf]
+  {
+    &self.roots
+  }
+
+  /// Resolve symlinks and `..` in `path` and check that it's inside an evidence root.
+  pub fn check_path(&self, path : &str) -> io::Result<PathBuf>
+  {
+    let resolved = fs::canonicalize(path).map_err(|err| io::Error::new(err.kind(), format!("Can't resolve path {} : {}", path, err)))?;
+
+    match self.roots.iter().any(|root| resolved.starts_with(root)) && !self.excluded.iter().any(|dir| resolved.starts_with(dir))
+    {
+      true => Ok(resolved),
+      false => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Path {} is outside of the allowed evidence directories", path))),
+    }
+  }
+
+  /// Check the arguments passed to a plugin, the paths in the arguments of a plugin
+  /// reading the server filesystem must be inside an evidence root.
+  /// Return the arguments with the paths replaced by their resolved path, so the plugin open the file that was checked.
+  pub fn check_arguments(&self, plugin_name : &str, arguments : &str) -> io::Result<String>
+  {
+    if !FILESYSTEM_PLUGINS.iter().any(|(name, _)| *name == plugin_name)
+    {
+      return Ok(arguments.to_string())
+    }
+
+    let mut arguments : Value = serde_json::from_str(arguments).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid arguments : {}", err)))?;
+    self.check_value(plugin_name, &mut arguments)?;
+    Ok(arguments.to_string())
+  }
+
+  /// Check and resolve the paths in the `arguments` of `plugin_name`.
+  pub fn check_value(&self, plugin_name : &str, arguments : &mut Value) -> io::Result<()>
+  {
+    match FILESYSTEM_PLUGINS.iter().find(|(name, _)| *name == plugin_name)
+    {
+      None => Ok(()),
+      Some((_, None)) => self.resolve_value(arguments),
+      //a missing path argument is reported by the plugin
+      Some((_, Some(argument))) => match arguments.get_mut(*argument)
+      {
+        Some(path) => self.resolve_value(path),
+        None => Ok(()),
+      },
+    }
+  }
+
+  fn resolve_value(&self, value : &mut Value) -> io::Result<()>
+  {
+    match value
+    {
+      Value::String(path) =>
+      {
+        let resolved = self.check_path(path)?;
+        *path = resolved.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Path {} is not valid UTF-8", resolved.display())))?.to_string();
+        Ok(())
+      },
+      Value::Array(values) => values.iter_mut().try_for_each(|value| self.resolve_value(value)),
+      Value::Object(values) => values.values_mut().try_for_each(|value| self.resolve_value(value)),
+      _ => Ok(()),
+    }
+  }
+
+  /// Check the arguments of the tasks of a saved case before they are replayed,
+  /// tasks are found in the save file as objects with a `plugin_name` and an `argument`.
+  pub fn check_saved_tasks(&self, file_name : &str) -> io::Result<()>
+  {
+    let file = File::open(file_name)?;
+    let saved : Value = serde_json::from_reader(BufReader::new(file))
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Can't check the tasks of {} : {}", file_name, err)))?;
+    self.check_saved_value(&saved)
+  }
+
+  fn check_saved_value(&self, value : &Value) -> io::Result<()>
+  {
+    match value
+    {
+      Value::Object(values) => match (values.get("plugin_name"), values.get("argument"))
+      {
+        (Some(Value::String(plugin_name)), Some(Value::String(argument))) => self.check_arguments(plugin_name, argument).map(|_| ()),
+        _ => values.values().try_for_each(|value| self.check_saved_value(value)),
+      },
+      Value::Array(values) => values.iter().try_for_each(|value| self.check_saved_value(value)),
+      _ => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  /// Directory with an `evidence` root, removed when dropped.
+  struct TestDir(PathBuf);
+
+  impl TestDir
+  {
+    fn new() -> Self
+    {
+      let dir = std::env::temp_dir().join(format!("tapir-sandbox-{}", uuid::Uuid::new_v4()));
+      fs::create_dir_all(dir.join("evidence")).unwrap();
+      TestDir(dir)
+    }
+
+    fn file(&self, name : &str) -> String
+    {
+      let path = self.0.join(name);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(&path, b"data").unwrap();
+      path.to_str().unwrap().to_string()
+    }
+
+    fn roots(&self) -> EvidenceRoots
+    {
+      EvidenceRoots::new(&[self.0.join("evidence")])
+    }
+  }
+
+  impl Drop for TestDir
+  {
+    fn drop(&mut self)
+    {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn is_denied<T>(result : io::Result<T>) -> bool
+  {
+    matches!(result, Err(err) if err.kind() == io::ErrorKind::PermissionDenied)
+  }
+
+  #[test]
+  fn path_inside_root_is_resolved()
+  {
+    let dir = TestDir::new();
+    let path = dir.file("evidence/disk.raw");
+    let resolved = dir.roots().check_path(&path).unwrap();
+    assert_eq!(resolved, fs::canonicalize(&path).unwrap());
+  }
+
+  #[test]
+  fn parent_traversal_is_denied()
+  {
+    let dir = TestDir::new();
+    dir.file("secret");
+    let path = dir.0.join("evidence").join("..").join("secret");
+    assert!(is_denied(dir.roots().check_path(path.to_str().unwrap())));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlink_escaping_root_is_denied()
+  {
+    let dir = TestDir::new();
+    let secret = dir.file("secret");
+    let link = dir.0.join("evidence").join("link");
+    std::os::unix::fs::symlink(&secret, &link).unwrap();
+    assert!(is_denied(dir.roots().check_path(link.to_str().unwrap())));
+  }
+
+  #[test]
+  fn excluded_dir_is_denied()
+  {
+    let dir = TestDir::new();
+    let path = dir.file("evidence/sessions/partial");
+    let roots = dir.roots().exclude(dir.0.join("evidence").join("sessions")).unwrap();
+    assert!(is_denied(roots.check_path(&path)));
+    assert!(roots.check_path(&dir.file("evidence/disk.raw")).is_ok());
+  }
+
+  #[test]
+  fn sibling_with_same_prefix_is_denied()
+  {
+    let dir = TestDir::new();
+    let path = dir.file("evidence2/disk.raw");
+    assert!(is_denied(dir.roots().check_path(&path)));
+  }
+
+  #[test]
+  fn every_string_is_a_path_for_local_and_device()
+  {
+    let dir = TestDir::new();
+    let inside = dir.file("evidence/disk.raw");
+    let outside = dir.file("secret");
+    let roots = dir.roots();
+
+    for plugin in ["local", "device"]
+    {
+      let arguments = roots.check_arguments(plugin, &serde_json::json!({"files" : [inside]}).to_string()).unwrap();
+      let expected = fs::canonicalize(&inside).unwrap();
+      assert_eq!(arguments, serde_json::json!({"files" : [expected]}).to_string());
+      assert!(is_denied(roots.check_arguments(plugin, &serde_json::json!({"path" : outside}).to_string())));
+    }
+  }
+
+  #[test]
+  fn only_the_rules_file_is_checked_for_yara()
+  {
+    let dir = TestDir::new();
+    let outside = dir.file("secret");
+    let roots = dir.roots();
+
+    let arguments = serde_json::json!({"file" : {"index1" : 1, "stamp" : 0}, "name" : "not a path"}).to_string();
+    assert_eq!(roots.check_arguments("yara", &arguments).unwrap(), arguments);
+    assert!(is_denied(roots.check_arguments("yara", &serde_json::json!({"rules_file" : outside}).to_string())));
+  }
+
+  #[test]
+  fn other_plugins_are_not_checked()
+  {
+    let dir = TestDir::new();
+    let arguments = serde_json::json!({"path" : dir.file("secret")}).to_string();
+    assert_eq!(dir.roots().check_arguments("hash", &arguments).unwrap(), arguments);
+  }
+}
@@ -0,0 +1,214 @@
+//! Named queries saved by the analysts, and a built-in library of common DFIR queries.
+
+use std::io;
+use std::fs::{self, File};
+use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+
+use crate::named::{self, Named};
+
+/// Suffix added to the file name of a saved case to get the file where the queries are saved.
+pub const QUERIES_SUFFIX : &str = ".queries.json";
+
+/// Name, description and query of the built-in queries, executed on `/root` by default.
+const LIBRARY : &[(&str, &str, &str)] = &[
+  ("executables", "Windows executables and libraries", "name matches '*.exe' or name matches '*.dll' or name matches '*.sys'"),
+  ("executables_in_temp", "Executables in temporary directories", "(name matches '*.exe' or name matches '*.dll') and (path matches '*/Temp/*' or path matches '*/tmp/*')"),
+  ("scripts", "PowerShell, VBScript, batch and JavaScript files", "name matches '*.ps1' or name matches '*.vbs' or name matches '*.bat' or name matches '*.cmd' or name matches '*.js'"),
+  ("lnk_files", "Shortcut files, that keep track of opened files and volumes", "name matches '*.lnk'"),
+  ("prefetch", "Prefetch files, that keep track of executed programs", "name matches '*.pf'"),
+  ("event_logs", "Windows event logs", "name matches '*.evtx'"),
+  ("registry_hives", "System and user registry hives", "name matches 'SYSTEM' or name matches 'SOFTWARE' or name matches 'SAM' or name matches 'SECURITY' or name matches 'NTUSER.DAT' or name matches 'UsrClass.dat'"),
+  ("startup_folders", "Programs launched at logon from the startup folders", "path matches '*/Start Menu/Programs/Startup/*'"),
+  ("scheduled_tasks", "Scheduled tasks definitions", "path matches '*/Windows/System32/Tasks/*'"),
+  ("browser_history", "Chrome, Edge, Firefox and Internet Explorer history databases", "name matches 'History' or name matches 'places.sqlite' or name matches 'WebCacheV01.dat'"),
+  ("recycle_bin", "Files deleted to the recycle bin", "path matches '*/$Recycle.Bin/*'"),
+];
+
+fn default_root() -> String
+{
+  String::from("/root")
+}
+
+/// A query saved under a name, executed on `root` unless another root is given when it's run.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SavedQuery
+{
+  pub name : String,
+  #[serde(default)]
+  pub description : String,
+  pub query : String,
+  #[serde(default = "default_root")]
+  pub root : String,
+  /// Query of the built-in library, that can't be modified.
+  #[serde(default, skip_deserializing)]
+  pub builtin : bool,
+}
+
+impl Named for SavedQuery
+{
+  const KIND : &'static str = "Query";
+
+  fn name(&self) -> &str
+  {
+    &self.name
+  }
+
+  fn is_builtin(&self) -> bool
+  {
+    self.builtin
+  }
+}
+
+/// Return the path of the file where the queries of a saved case are written.
+pub fn queries_path(file_name : &str) -> PathBuf
+{
+  PathBuf::from(file_name.to_owned() + QUERIES_SUFFIX)
+}
+
+/// Queries of the built-in library and queries created by the users,
+/// the queries created by the users are written to a file after every change so they are kept when the server restart.
+pub struct SavedQueries
+{
+  file_path : PathBuf,
+  queries : RwLock<Vec<SavedQuery>>,
+}
+
+impl SavedQueries
+{
+  /// Create the list with the queries of the built-in library and the queries written to `file_path`, if it exists.
+  pub fn open<P : AsRef<Path>>(file_path : P) -> io::Result<Self>
+  {
+    let queries = LIBRARY.iter().map(|(name, description, query)| SavedQuery{ name : name.to_string(), description : description.to_string(),
+                                                                            query : query.to_string(), root : default_root(), builtin : true }).collect();
+    let saved_queries = SavedQueries{ file_path : file_path.as_ref().to_path_buf(), queries : RwLock::new(queries) };
+    saved_queries.load(&saved_queries.file_path)?;
+    Ok(saved_queries)
+  }
+
+  fn check(query : &SavedQuery) -> io::Result<()>
+  {
+    if query.name.trim().is_empty() || query.query.trim().is_empty()
+    {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "Name and query can't be empty"))
+    }
+    Ok(())
+  }
+
+  /// Write the queries created by the users to `path`, the file is replaced only once it's completely written.
+  fn write(queries : &[SavedQuery], path : &Path) -> io::Result<()>
+  {
+    let queries : Vec<&SavedQuery> = queries.iter().filter(|query| !query.builtin).collect();
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let file = File::create(&temp_path)?;
+    serde_json::to_writer_pretty(&file, &queries).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)
+  }
+
+  /// Change the queries with `change`, the change is kept only if it was written to the queries file.
+  fn update<F>(&self, change : F) -> io::Result<()>
+    where F : FnOnce(&mut Vec<SavedQuery>) -> io::Result<()>
+  {
+    let mut queries = self.queries.write().unwrap();
+    let mut updated = queries.clone();
+    change(&mut updated)?;
+    Self::write(&updated, &self.file_path)?;
+    *queries = updated;
+    Ok(())
+  }
+
+  pub fn list(&self) -> Vec<SavedQuery>
+  {
+    self.queries.read().unwrap().clone()
+  }
+
+  pub fn get(&self, name : &str) -> io::Result<SavedQuery>
+  {
+    named::get(&self.queries.read().unwrap(), name).cloned()
+  }
+
+  pub fn add(&self, mut query : SavedQuery) -> io::Result<()>
+  {
+    Self::check(&query)?;
+    query.builtin = false;
+    self.update(|queries| named::add(queries, query))
+  }
+
+  pub fn replace(&self, name : &str, mut query : SavedQuery) -> io::Result<()>
+  {
+    Self::check(&query)?;
+    query.builtin = false;
+    self.update(|queries| named::replace(queries, name, query))
+  }
+
+  pub fn remove(&self, name : &str) -> io::Result<()>
+  {
+    self.update(|queries| named::remove(queries, name).map(|_| ()))
+  }
+
+  /// Write the queries created by the users to `path`, next to a saved case.
+  pub fn save(&self, path : &Path) -> io::Result<()>
+  {
+    Self::write(&self.queries.read().unwrap(), path)
+  }
+
+  /// Replace the queries created by the users by the queries saved in `path`.
+  /// Return the name of the queries that were not loaded because their name is already used, by a built-in query or by another query of the file,
+  /// or `None` and keep the current queries if `path` doesn't exist.
+  pub fn load(&self, path : &Path) -> io::Result<Option<Vec<String>>>
+  {
+    if !path.exists()
+    {
+      return Ok(None)
+    }
+    let file = File::open(path)?;
+    let loaded : Vec<SavedQuery> = serde_json::from_reader(file).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    let mut skipped = Vec::new();
+    self.update(|queries|
+    {
+      queries.retain(|query| query.builtin);
+      for query in loaded
+      {
+        let name = query.name.clone();
+        if let Err(err) = named::add(queries, query)
+        {
+          warn!("Query {} of {} not loaded : {}", name, path.display(), err);
+          skipped.push(name);
+        }
+      }
+      Ok(())
+    })?;
+    info!("Loaded saved queries from {}", path.display());
+    Ok(Some(skipped))
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  use tap::session::Session;
+  use ::tap_query::filter::Filter;
+
+  #[test]
+  fn library_queries_are_valid()
+  {
+    let session = Session::new();
+    for (name, _, query) in LIBRARY
+    {
+      if let Err(err) = Filter::path(&session.tree, query, "/root")
+      {
+        panic!("Built-in query {} is invalid : {}", name, err);
+      }
+    }
+  }
+}
@@ -1,54 +1,80 @@
 use std::error::Error;
-use std::path::PathBuf;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::io::Read;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek};
 use std::io::SeekFrom;
 use std::io::Write;
 
 use tap::session::Session;
 use tap::tree::TreeNodeId;
-use tap::task_scheduler::{TaskId,TaskState};
 use tap::node::Node;
 use ::tap_save::Save;
 use ::tap_query::filter::Filter;
 use ::tap_query::timeline as query_timeline;
 use ::tap_query::attribute::attribute_count as query_attribute_count;
 
-use crate::asyncvfile::AsyncVFile;
+use crate::asyncvfile::{AsyncVFile, Content};
+use crate::range::{RangeHeaders, ByteRanges};
+use crate::auth::{User, Users, Viewer, Analyst, Admin};
+use crate::audit::{AuditLog, AuditEntry, AuditFilter, AuditOutcome};
+use crate::tls;
+use crate::error::{self as api_error, ApiError};
+use crate::sandbox::EvidenceRoots;
+use crate::upload::{Conflict, ExpectedHashes, UploadInfo, store as store_upload, list_uploads};
+use crate::uploadsession::{UploadSessions, UploadSession, NewUploadSession};
+use crate::export::{ArchiveFormat, export_nodes};
+use crate::taskqueue::{TaskQueue, TaskStatus, QueuedTask, BatchInfo, TaskFilter, TaskHistory};
+use crate::events::{Event, EventFilter, Events};
+use crate::pipeline::{PipelineRule, Pipelines, apply_template};
+use crate::openapi::{ApiDocument, Body, Describe, NodeId, Parameter, document as api_document};
+use crate::page::{Order, Page, PageRequest, SortedNodes};
+use crate::savedquery::{SavedQuery, SavedQueries, queries_path};
+use crate::querycache::QueryCache;
+use crate::aggregate::{Aggregation, Bucket, aggregate as aggregate_nodes};
 #[cfg(feature = "frontend")]
 use crate::staticfileserver::StaticFileServer;
 #[cfg(feature = "frontend")]
 use webbrowser;
 
-use log::info;
+use log::{info, warn};
+use uuid::Uuid;
 use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
 use chrono::{DateTime, Utc};
 use json_value_merge::Merge;
 use serde::ser::{SerializeSeq, Serializer};
 
 use rocket::State;
+use rocket::Shutdown;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::response::stream::{Event as SseEvent, EventStream};
 use rocket::shield::Shield;
 use rocket::config::Config;
-#[cfg(feature = "tls")]
 use rocket::config::TlsConfig;
 use rocket::{Request, Response};
-use rocket::http::{self, Status, Header};
-use rocket::response::status::{BadRequest, Custom};
+use rocket::http::{self, Status, Header, ContentType};
 use rocket::serde::json::{Json,json,Value};
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::data::{Data, Limits, ToByteUnit};
 #[cfg(feature = "frontend-dev")]
 use rocket::fs::FileServer;
-use rocket::request::{Outcome, FromRequest};
+#[cfg(feature = "api-docs")]
+use rocket::response::content::RawHtml;
 
+/// Run a blocking closure on a thread, a panic of the closure is returned as an `ApiError`.
 macro_rules! spawn_thread
 {
   ($closure:expr) => 
   {
     rocket::tokio::task::spawn_blocking(move || {
       $closure
-    }).await.unwrap()
+    }).await?
   };
 }
 
@@ -57,12 +83,24 @@ pub struct Arguments
 {
   pub address : SocketAddr,
   pub upload : String,
-  pub api_key : String,
+  pub users : Vec<User>,
+  pub audit : String,
+  /// Directories from which the local plugin can load files, the upload directory is always included.
+  pub evidence_roots : Vec<String>,
+  pub tls_cert : Option<String>,
+  pub tls_key : Option<String>,
+  /// Generate a self-signed certificate if `tls_cert` or `tls_key` doesn't exist.
+  pub tls_self_signed : bool,
+  /// Maximum number of tasks running at the same time.
+  pub max_tasks : usize,
+  /// Maximum number of tasks running at the same time for some plugins.
+  pub concurrency : HashMap<String, usize>,
+  pub pipelines : Vec<PipelineRule>,
 }
 
 pub type ArcSession = Arc<Session>;
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct PluginInfo
 {
   pub name : String,
@@ -71,9 +109,25 @@ pub struct PluginInfo
   pub config : Option<Value>,
 }
 
+/// Parse the configuration schema of a plugin, the plugin is returned without schema if it's invalid.
+fn plugin_config<E : Display>(plugin_name : &str, config : Result<String, E>) -> Option<Value>
+{
+  let config = config.map_err(|err| err.to_string())
+                     .and_then(|config| serde_json::from_str(&config).map_err(|err| err.to_string()));
+  match config
+  {
+    Ok(config) => Some(config),
+    Err(err) => 
+    {
+      warn!("Invalid config for plugin {} : {}", plugin_name, err);
+      None
+    },
+  }
+}
+
 ///Return list of available plugins. 
 #[get("/plugins")]
-async fn plugins(_key : ApiKey<'_>, session : &State<ArcSession>) -> Json<Vec<PluginInfo>> 
+async fn plugins(_user : Viewer, session : &State<ArcSession>) -> Result<Json<Vec<PluginInfo>>, ApiError>
 {
   let session = session.inner().clone();
   rocket::tokio::task::spawn_blocking(move || {
@@ -82,16 +136,16 @@ async fn plugins(_key : ApiKey<'_>, session : &State<ArcSession>) -> Json<Vec<Pl
                                          .map(|plugin| PluginInfo{ name : plugin.name().into(),
                                               category : plugin.category().into(),
                                               description : plugin.help().into(),
-                                              config : serde_json::from_str(&plugin.config().unwrap()).unwrap(), })
+                                              config : plugin_config(plugin.name(), plugin.config()), })
                                          .collect();
-  Json(plugins)
-  }).await.unwrap()
+  Ok(Json(plugins))
+  }).await?
 }
 
 
 ///Return plugin configuration schema.
 #[get("/plugin/<plugin_name>", format = "json")] 
-async fn plugin(_key : ApiKey<'_>, session : &State<ArcSession>, plugin_name : String) -> Json<Option<PluginInfo>>
+async fn plugin(_user : Viewer, session : &State<ArcSession>, plugin_name : String) -> Result<Json<PluginInfo>, ApiError>
 {
   let session = session.inner().clone();
   rocket::tokio::task::spawn_blocking(move || {
@@ -99,52 +153,77 @@ async fn plugin(_key : ApiKey<'_>, session : &State<ArcSession>, plugin_name : S
      name : plugin.name().into(),
      category : plugin.category().into(),
      description : plugin.help().into(),
-     config : serde_json::from_str(&plugin.config().ok().unwrap()).unwrap(), 
+     config : plugin_config(plugin.name(), plugin.config()), 
   });
   
-  Json(plugin_info)
-  }).await.unwrap()
+  plugin_info.map(Json).ok_or_else(|| ApiError::not_found("plugin_not_found", format!("Plugin {} not found", plugin_name)))
+  }).await?
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct NodeIdOption
 {
+  #[schemars(with = "NodeId")]
   pub node_id : TreeNodeId,
   pub option : NodeOption,
 }
 
 impl NodeIdOption
 {
-  fn to_json(&self, session : &Session) -> Result<Value, BadRequest<String>>
+  fn to_json(&self, session : &Session) -> Result<Value, ApiError>
   {
     node_option_to_json(session, &self.node_id, &self.option)
   }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct NodesIdOption 
 {
+  #[schemars(with = "Vec<NodeId>")]
   pub nodes_id : Vec<TreeNodeId>,
   pub option : NodeOption,
 }
 
 impl NodesIdOption
 {
-  fn to_json<W>(&self, session : &Session, writer : W) -> W 
+  fn to_json<W>(&self, session : &Session, writer : W) -> Result<W, ApiError>
     where W: Write, 
   {
     let mut ser = serde_json::Serializer::new(writer);   
-    let mut seq = ser.serialize_seq(None).unwrap();
+    NodesJson{ session, nodes_id : &self.nodes_id, option : Some(&self.option), attributes : None }.serialize(&mut ser)?;
+    Ok(ser.into_inner())
+  }
+}
+
+/// Serialize the nodes one by one as a JSON array, a node that can't be found is returned as `{"id", "error"}`.
+struct NodesJson<'a>
+{
+  session : &'a Session,
+  nodes_id : &'a [TreeNodeId],
+  option : Option<&'a NodeOption>,
+  attributes : Option<&'a [String]>,
+}
+
+impl Serialize for NodesJson<'_>
+{
+  fn serialize<S>(&self, serializer : S) -> Result<S::Ok, S::Error>
+    where S : Serializer,
+  {
+    let mut seq = serializer.serialize_seq(Some(self.nodes_id.len()))?;
     for node_id in self.nodes_id.iter()
     {
-      seq.serialize_element(&node_option_to_json(session, node_id, &self.option).unwrap()).unwrap(); 
+      let node = match node_projection_to_json(self.session, node_id, self.option, self.attributes)
+      {
+        Ok(node) => node,
+        Err(err) => json!({"id" : node_id, "error" : err}),
+      };
+      seq.serialize_element(&node)?; 
     }
-     seq.end().unwrap(); 
-     ser.into_inner()
+    seq.end()
   }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct NodeOption
 {
   pub name : bool,
@@ -153,12 +232,12 @@ pub struct NodeOption
   pub children : bool,
 }
 
-fn node_option_to_json(session : &Session, node_id : &TreeNodeId, option : &NodeOption) -> Result<Value, BadRequest<String>>
+fn node_option_to_json(session : &Session, node_id : &TreeNodeId, option : &NodeOption) -> Result<Value, ApiError>
 {
   let node = match session.tree.get_node_from_id(*node_id)
   {
     Some(node) => node,
-    None => return Err(BadRequest(Some("Node didn't exist".to_string()))),
+    None => return Err(ApiError::node_not_found()),
   };
   let name = match option.name
   {
@@ -188,9 +267,29 @@ fn node_option_to_json(session : &Session, node_id : &TreeNodeId, option : &Node
 }
 
 
+/// Return a node with the fields selected by `option`, 
+/// and only the attributes listed in `attributes` if set, as `{"name" : value}` with a null value for missing attributes.
+fn node_projection_to_json(session : &Session, node_id : &TreeNodeId, option : Option<&NodeOption>, attributes : Option<&[String]>) -> Result<Value, ApiError>
+{
+  let mut node_json = match option
+  {
+    Some(option) => node_option_to_json(session, node_id, option)?,
+    None => json!({"id" : node_id}),
+  };
+
+  if let Some(attributes) = attributes
+  {
+    let node = session.tree.get_node_from_id(*node_id).ok_or_else(ApiError::node_not_found)?;
+    let values : serde_json::Map<String, Value> = attributes.iter().map(|name| (name.clone(), json!(node.value().get_value(name)))).collect();
+    node_json["attributes"] = Value::Object(values);
+  }
+
+  Ok(node_json)
+}
+
 ///Return a node from a node id.
 #[post("/node", data = "<node_option>", format = "json")]
-async fn node(_key : ApiKey<'_>, session : &State<ArcSession>, node_option : Json<NodeIdOption>) -> Result<Value, BadRequest<String>>
+async fn node(_user : Viewer, session : &State<ArcSession>, node_option : Json<NodeIdOption>) -> Result<Value, ApiError>
 {
   let session = session.inner().clone();
   spawn_thread!(node_option.to_json(&session))
@@ -198,7 +297,7 @@ async fn node(_key : ApiKey<'_>, session : &State<ArcSession>, node_option : Jso
 
 ///Return root node.
 #[get("/root")]
-async fn root(_key : ApiKey<'_>,  session : &State<ArcSession>) -> Result<Value, BadRequest<String>>
+async fn root(_user : Viewer,  session : &State<ArcSession>) -> Result<Value, ApiError>
 {
   let session = session.inner().clone();
 
@@ -206,12 +305,12 @@ async fn root(_key : ApiKey<'_>,  session : &State<ArcSession>) -> Result<Value,
   let node_id = session.tree.root_id;
   let option = NodeOption{ name : true, path : false, attributes : true, children : true};
   node_option_to_json(&session, &node_id, &option)
-  }).await.unwrap()
+  }).await?
 }
 
 ///Return a node from a path.
 #[get("/root/<path..>")]
-async fn node_by_path(_key : ApiKey<'_>, session : &State<ArcSession>, path : PathBuf) -> Result<Value, BadRequest<String>>
+async fn node_by_path(_user : Viewer, session : &State<ArcSession>, path : PathBuf) -> Result<Value, ApiError>
 {
   let session = session.inner().clone();
 
@@ -219,24 +318,24 @@ async fn node_by_path(_key : ApiKey<'_>, session : &State<ArcSession>, path : Pa
   let path = match path.to_str()
   {
     Some(path) => path,
-    None => return Err(BadRequest(Some("Can't convert path to str".into()))),
+    None => return Err(ApiError::bad_request("invalid_path", "Can't convert path to str")),
   };
 
   let path = path.replace('\\', "/"); //temp fix for windows
   let node_id = match session.tree.get_node_id(&("/root/".to_owned() + &path))
   {
     Some(node_id) => node_id,
-    None => return Err(BadRequest(Some("Node id not found".into()))),
+    None => return Err(ApiError::node_not_found()),
   };
   
   let option = NodeOption{ name : true, path : false, attributes : true, children : true};
   node_option_to_json(&session, &node_id, &option)
-  }).await.unwrap()
+  }).await?
 }
 
 ///Return a list of nodes attributes from a vector of node_id.
 #[post("/nodes", data = "<request>", format = "json")]
-async fn nodes(_key : ApiKey<'_>, session : &State<ArcSession>, request: Json<NodesIdOption>) -> Vec<u8> 
+async fn nodes(_user : Viewer, session : &State<ArcSession>, request: Json<NodesIdOption>) -> Result<Vec<u8>, ApiError>
 {
   let session = session.inner().clone();
 
@@ -245,21 +344,30 @@ async fn nodes(_key : ApiKey<'_>, session : &State<ArcSession>, request: Json<No
   let writer = Vec::new();
   request.to_json(&session, writer)
 
-  }).await.unwrap()
+  }).await?
 }
 
 ///Remove node and descendants.
 #[post("/delete", data="<node_id>")]
-async fn delete(_key : ApiKey<'_>, session : &State<ArcSession>, node_id : Json<TreeNodeId>)
+async fn delete(user : Admin, session : &State<ArcSession>, audit_log : &State<AuditLog>, events : &State<Events>, query_cache : &State<Arc<QueryCache>>, node_id : Json<TreeNodeId>) -> Result<(), ApiError>
 {
   let node_id : TreeNodeId = *node_id;
 
   let session = session.inner().clone();
-  spawn_thread!(session.tree.remove(node_id))
+  let path = spawn_thread!({
+    let path = session.tree.node_path(node_id).ok_or_else(ApiError::node_not_found)?;
+    session.tree.remove(node_id).map_err(|err| ApiError::bad_request("delete_failed", err.to_string()))?;
+    Ok::<String, ApiError>(path)
+  });
+  query_cache.invalidate();
+  audit_log.record(&user.0, "delete", json!(node_id), AuditOutcome::from_result(&path.as_ref().map(|_| ()))).await;
+
+  events.send(Event::NodeDeleted{ node_id, path : path? });
+  Ok(())
 }
 
 /*#[get("/clear")]
-async fn clear(_key : ApiKey<'_>, session : &State<ArcSession>)
+async fn clear(_user : Admin, session : &State<ArcSession>)
 {
   let session = session.inner().clone();
   spawn_thread!(session.write().unwrap().clear());
@@ -267,155 +375,339 @@ async fn clear(_key : ApiKey<'_>, session : &State<ArcSession>)
 
 ///Return a path from a node id.
 #[post("/path", data = "<node_id>")]
-async fn path(_key : ApiKey<'_>, session : &State<ArcSession>, node_id : Json<TreeNodeId>) -> Result<String, BadRequest<String>>
+async fn path(_user : Viewer, session : &State<ArcSession>, node_id : Json<TreeNodeId>) -> Result<String, ApiError>
 {
   let node_id : TreeNodeId = *node_id;
 
   let session = session.inner().clone();
-  spawn_thread!(
-    match session.tree.node_path(node_id)
-    {
-      Some(path) => Ok(path),
-      None => Err(BadRequest(Some("Can't find node".into()))),
-    })
+  spawn_thread!(session.tree.node_path(node_id).ok_or_else(ApiError::node_not_found))
 }
 
 ///Return parent id from node id.
 #[post("/parent_id", data = "<node_id>", format = "json")]
-async fn parent_id(_key : ApiKey<'_>, session : &State<ArcSession>, node_id : Json<TreeNodeId>) -> Json<Option<TreeNodeId>>
+async fn parent_id(_user : Viewer, session : &State<ArcSession>, node_id : Json<TreeNodeId>) -> Result<Json<Option<TreeNodeId>>, ApiError>
 {
   let node_id : TreeNodeId = *node_id;
 
   let session = session.inner().clone();
-  spawn_thread!(Json(session.tree.parent_id(node_id)))
+  Ok(spawn_thread!(Json(session.tree.parent_id(node_id))))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 struct PluginArgs
 {
   name : String,
   arguments : String,
   relaunch : bool, 
+  /// Tasks with a higher priority are launched first.
+  #[serde(default)]
+  priority : i32,
 }
 
 ///Run a task and block until task end and return task result.
+///The task goes through the queue so it follows the priorities and the plugins limits.
 #[post("/run", data = "<plugin>", format = "json")]
-async fn run(_key : ApiKey<'_>, session : &State<ArcSession>, plugin : Json<PluginArgs>) -> Value
+async fn run(user : Analyst, session : &State<ArcSession>, task_queue : &State<Arc<TaskQueue>>, audit_log : &State<AuditLog>, evidence_roots : &State<Arc<EvidenceRoots>>, plugin : Json<PluginArgs>) -> Result<Value, ApiError>
 {
   info!("run : {} {}", plugin.name, plugin.arguments);
   let session = session.inner().clone();
-  
-  let result = spawn_thread!(session.run(&plugin.name, plugin.arguments.clone(), plugin.relaunch));
+  let arguments = json!(&*plugin);
 
-  match result 
+  let checked = match session.plugins_db.find(&plugin.name)
   {
-    Ok(result) => json!({"result" : result}), 
-    Err(err) => json!({"error" : err.to_string()}), 
-  }
+    Some(_) => evidence_roots.check_arguments(&plugin.name, &plugin.arguments).map_err(ApiError::from),
+    None => Err(ApiError::not_found("plugin_not_found", format!("Plugin {} not found", plugin.name))),
+  };
+  let plugin_arguments = match checked
+  {
+    Ok(plugin_arguments) => plugin_arguments,
+    Err(err) =>
+    {
+      audit_log.record(&user.0, "run", arguments, AuditOutcome::error(&err)).await;
+      return Err(err)
+    },
+  };
+  
+  let task_queue = task_queue.inner().clone();
+  let task = spawn_thread!(task_queue.push(&plugin.name, &plugin_arguments, plugin.relaunch, plugin.priority).and_then(|id| task_queue.wait(id)));
+  let result = match task
+  {
+    Ok(task) => match (task.state, task.error) 
+    {
+      (TaskStatus::Cancelled, _) => Err(ApiError::unprocessable("task_failed", format!("Task {} was cancelled", task.id))),
+      (_, Some(err)) => Err(ApiError::unprocessable("task_failed", err)),
+      (_, None) => Ok(task.result.unwrap_or(Value::Null)),
+    },
+    Err(err) => Err(err.into()),
+  };
+  audit_log.record(&user.0, "run", arguments, AuditOutcome::from_result(&result)).await;
+
+  result.map(|result| json!({"result" : result}))
 }
 
-///Schedule a task to be run on the server and return the created task state id or an error.
+///Schedule a task to be run on the server and return the created task id or an error.
 #[post("/schedule", data = "<plugin>", format = "json")] 
-async fn schedule(_key : ApiKey<'_>, session : &State<ArcSession>, plugin : Json<PluginArgs>) -> Result<Json<TaskId>, BadRequest<String>>
+async fn schedule(user : Analyst, task_queue : &State<Arc<TaskQueue>>, audit_log : &State<AuditLog>, evidence_roots : &State<Arc<EvidenceRoots>>, plugin : Json<PluginArgs>) -> Result<Json<u32>, ApiError>
 {
   info!("Scheduling : {} {}", plugin.name, plugin.arguments);
   
-  let session = session.inner().clone();
+  let arguments = json!(&*plugin);
+
+  let plugin_arguments = match evidence_roots.check_arguments(&plugin.name, &plugin.arguments)
+  {
+    Ok(plugin_arguments) => plugin_arguments,
+    Err(err) =>
+    {
+      audit_log.record(&user.0, "schedule", arguments, AuditOutcome::error(&err)).await;
+      return Err(err.into())
+    },
+  };
   
-  let result = spawn_thread!(session.schedule(&plugin.name, plugin.arguments.clone(), plugin.relaunch));
+  let result = task_queue.push(&plugin.name, &plugin_arguments, plugin.relaunch, plugin.priority);
   info!("Result : {:?}", result);
+  audit_log.record(&user.0, "schedule", arguments, AuditOutcome::from_result(&result)).await;
 
-  match result
+  result.map(Json).map_err(ApiError::from)
+}
+
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+struct ScheduleQueryInfo
+{
+  /// Plugin name.
+  name : String,
+  /// Arguments of the plugin, where the `{node_id}` strings are replaced by the id of each node.
+  arguments : Value,
+  query : String,
+  root : String,
+  #[serde(default)]
+  relaunch : bool,
+  #[serde(default)]
+  priority : i32,
+}
+
+/// Schedule a plugin on each node returned by a query, return the batch id and the id of the tasks.
+#[post("/schedule_query", data = "<info>", format = "json")]
+async fn schedule_query(user : Analyst, session : &State<ArcSession>, task_queue : &State<Arc<TaskQueue>>, audit_log : &State<AuditLog>, 
+                        evidence_roots : &State<Arc<EvidenceRoots>>, info : Json<ScheduleQueryInfo>) -> Result<Value, ApiError>
+{
+  info!("Scheduling {} on query {} from {}", info.name, info.query, info.root);
+  let audit_arguments = json!(&*info);
+
+  let session = session.inner().clone();
+  let query_info = info.into_inner();
+  let (query, root, template) = (query_info.query.clone(), query_info.root.clone(), query_info.arguments.clone());
+  let arguments = spawn_thread!(
+    Filter::path(&session.tree, &query, &root)
+      .map(|nodes_id| nodes_id.into_iter().map(|node_id| apply_template(&template, node_id).to_string()).collect::<Vec<String>>())
+      .map_err(|err| ApiError::bad_request("invalid_query", err.to_string()))
+  );
+
+  let result = arguments.and_then(|arguments|
   {
-    Ok(id) => Ok(Json(id)), 
-    Err(err) => Err(BadRequest(Some(err.to_string()))),
-  }
+    let arguments = arguments.iter().map(|arguments| evidence_roots.check_arguments(&query_info.name, arguments)).collect::<Result<Vec<String>, _>>()?;
+    Ok(task_queue.push_batch(&query_info.name, &arguments, query_info.relaunch, query_info.priority)?)
+  });
+  audit_log.record(&user.0, "schedule_query", audit_arguments, AuditOutcome::from_result(&result.as_ref().map(|(batch, tasks)| json!({"batch" : batch, "count" : tasks.len()})))).await;
+
+  result.map(|(batch, tasks)| json!({"batch" : batch, "tasks" : tasks}))
+}
+
+/// Return the state of the tasks of a batch.
+#[post("/batch?<batch_id>")]
+async fn batch(_user : Viewer, task_queue : &State<Arc<TaskQueue>>, batch_id : u32) -> Result<Json<BatchInfo>, ApiError>
+{
+  task_queue.batch(batch_id).map(Json).ok_or_else(|| ApiError::not_found("batch_not_found", format!("Batch {} not found", batch_id)))
 }
 
+/// Cancel all the tasks of a batch that are not finished and return their id.
+#[post("/batch/cancel?<batch_id>")]
+async fn batch_cancel(user : Analyst, task_queue : &State<Arc<TaskQueue>>, audit_log : &State<AuditLog>, batch_id : u32) -> Json<Vec<u32>>
+{
+  let cancelled = task_queue.cancel_batch(batch_id);
+  audit_log.record(&user.0, "batch_cancel", json!({"batch_id" : batch_id}), AuditOutcome::Success{ result : json!(cancelled) }).await;
+
+  Json(cancelled)
+}
 
 /// Wait that all tasks are finished.
 #[post("/join")]
-async fn join(_key : ApiKey<'_>, session : &State<ArcSession>)
+async fn join(_user : Analyst, session : &State<ArcSession>, task_queue : &State<Arc<TaskQueue>>) -> Result<(), ApiError>
 {
   info!("joining on task");
   let session = session.inner().clone();
-  spawn_thread!(session.join());
+  let task_queue = task_queue.inner().clone();
+  spawn_thread!({
+    task_queue.join();
+    //tasks replayed by load are not in the queue
+    session.join();
+  });
+  Ok(())
 }
 
 /// Return the coutn of task.
 #[post("/task_count")]
-async fn task_count(_key : ApiKey<'_>, session : &State<ArcSession>) -> Value
+async fn task_count(_user : Viewer, session : &State<ArcSession>) -> Result<Value, ApiError>
 {
   let session = session.inner().clone();
-  spawn_thread!(json!(session.task_scheduler.task_count()))
+  Ok(spawn_thread!(json!(session.task_scheduler.task_count())))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct TasksParameters
 {
   ids : Vec<u32>,
 }
 
-/// Return task state and task info.
+/// State of a task returned by `/tasks`.
+#[derive(Serialize, JsonSchema)]
+struct TaskEntry
+{
+  /// `waiting`, `running`, `finished` or `cancelled`.
+  state : &'static str,
+  id : u32,
+  plugin : String,
+  argument : Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  result : Option<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error : Option<String>,
+  /// Queue info of the tasks scheduled through the queue.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  queue : Option<QueuedTask>,
+}
+
+/// State of a task returned by `/task`.
+#[derive(Serialize, JsonSchema)]
+struct TaskResponse
+{
+  /// `waiting`, `running`, `finished` or `cancelled`.
+  state : &'static str,
+  /// The task `id`, `plugin_name` and `argument`.
+  task : Value,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  result : Option<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error : Option<String>,
+  /// Queue info of the tasks scheduled through the queue.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  queue : Option<QueuedTask>,
+}
+
+/// Split the result of a task in the `result` and `error` fields of the responses.
+fn task_outcome(result : Option<Result<Value, String>>) -> (Option<Value>, Option<String>)
+{
+  match result
+  {
+    Some(Ok(result)) => (Some(result), None),
+    Some(Err(err)) => (None, Some(err)),
+    None => (None, None),
+  }
+}
+
+/// Return task state and task info, tasks ids are the ids of the session task scheduler or of the queue.
 #[post("/tasks", data="<parameters>", format = "json")] 
-async fn tasks(_key : ApiKey<'_>, session : &State<ArcSession>, parameters : Json<TasksParameters>) -> Json<Vec<Value>> 
+async fn tasks(_user : Viewer, task_queue : &State<Arc<TaskQueue>>, parameters : Json<TasksParameters>) -> Result<Json<Vec<TaskEntry>>, ApiError> 
 {
-  let session = session.inner().clone();
+  let task_queue = task_queue.inner().clone();
   let parameters = parameters.into_inner();
 
-  rocket::tokio::task::spawn_blocking(move || {
+  Ok(Json(spawn_thread!(parameters.ids.iter().filter_map(|id| task_queue.task_info(*id)).map(|info|
+  {
+    let (result, error) = task_outcome(info.result);
+    TaskEntry{ state : info.state, id : info.id, plugin : info.plugin, argument : info.argument, result, error, queue : info.queued }
+  }).collect::<Vec<TaskEntry>>())))
+}
 
-  let tasks_state = session.task_scheduler.tasks(parameters.ids);
-  let mut response = Vec::new();
+/// Return task state and task info, `task_id` is the id of the session task scheduler or of the queue.
+#[post("/task?<task_id>")] 
+async fn task(_user : Viewer, task_queue : &State<Arc<TaskQueue>>, task_id : u32) -> Result<Json<TaskResponse>, ApiError> 
+{
+  let task_queue = task_queue.inner().clone();
+  let info = spawn_thread!(task_queue.task_info(task_id)).ok_or_else(|| ApiError::not_found("task_not_found", format!("Task {} not found", task_id)))?;
 
-  for task_state in tasks_state
-  {
-    let (task, result) = match task_state
-    {
-      TaskState::Waiting(task) => { response.push(json!({"state" : "waiting",  "id" : task.id, "plugin": task.plugin_name, "argument" : serde_json::from_str::<Value>(&task.argument).unwrap() })); break; },
-      TaskState::Launched(task) => { response.push(json!({"state" : "running", "id" : task.id, "plugin": task.plugin_name, "argument" : serde_json::from_str::<Value>(&task.argument).unwrap() })); break; },
-      TaskState::Finished(task, result) => (task, result),
-    };
+  let (result, error) = task_outcome(info.result);
+  Ok(Json(TaskResponse{ state : info.state, task : info.task, result, error, queue : info.queued }))
+}
 
-    let result = match result
-    {
-      Ok(result) => json!({"result" : result, "state" : "finished", "id" : task.id, "plugin": task.plugin_name, "argument" : serde_json::from_str::<Value>(&task.argument).unwrap() }), 
-      Err(err) => json!({"error" : err.to_string(), "state" : "finished", "id" : task.id, "plugin": task.plugin_name, "argument" : serde_json::from_str::<Value>(&task.argument).unwrap() }),
-    };
-    response.push(result);
-  }  
-  Json(response)
+/// Return the tasks, optionally filtered by plugin, state and queued time, with the summary of their timings by plugin.
+#[get("/tasks/history?<plugin>&<state>&<after>&<before>")]
+async fn tasks_history(_user : Viewer, task_queue : &State<Arc<TaskQueue>>, plugin : Option<String>, state : Option<String>,
+                       after : Option<String>, before : Option<String>) -> Result<Json<TaskHistory>, ApiError>
+{
+  let filter = TaskFilter{ plugin, state, after : parse_date(after)?, before : parse_date(before)? };
+  let task_queue = task_queue.inner().clone();
 
-  }).await.unwrap()
+  Ok(Json(spawn_thread!(task_queue.history(&filter))?))
 }
 
-/// Return task state and task info.
-#[post("/task?<task_id>")] 
-async fn task(_key : ApiKey<'_>, session : &State<ArcSession>, task_id : u32) -> Option<Value> 
+/// Cancel a task, a running task can't be interrupted and is only flagged with `cancel_requested`.
+#[post("/task/cancel?<task_id>")]
+async fn task_cancel(user : Analyst, task_queue : &State<Arc<TaskQueue>>, audit_log : &State<AuditLog>, task_id : u32) -> Result<Json<QueuedTask>, ApiError>
 {
-  let session = session.inner().clone();
-  let task_state = spawn_thread!(session.task_scheduler.task(task_id))?;
+  let result = task_queue.cancel(task_id);
+  audit_log.record(&user.0, "task_cancel", json!({"task_id" : task_id}), AuditOutcome::from_result(&result.as_ref().map(|task| task.state))).await;
 
-  let (task, result) = match task_state
-  {
-    TaskState::Waiting(task) => return Some(json!({"state" : "waiting", "task" : task})),
-    TaskState::Launched(task) => return Some(json!({"state" : "running", "task" : task})),
-    TaskState::Finished(task, result) => (task, result),
-  };
+  result.map(Json).map_err(ApiError::from)
+}
 
-  match result
-  {
-    Ok(result) => Some(json!({"result" : result, "state" : "finished", "task" : task })), 
-    Err(err) => Some(json!({"error" : err.to_string(), "state" : "finished", "task" : task})), 
-  }
+/// Cancel all the tasks of a plugin that are not finished and return their id.
+#[post("/tasks/cancel?<plugin>")]
+async fn tasks_cancel(user : Analyst, task_queue : &State<Arc<TaskQueue>>, audit_log : &State<AuditLog>, plugin : String) -> Json<Vec<u32>>
+{
+  let cancelled = task_queue.cancel_plugin(&plugin);
+  audit_log.record(&user.0, "tasks_cancel", json!({"plugin" : plugin}), AuditOutcome::Success{ result : json!(cancelled) }).await;
+
+  Json(cancelled)
+}
+
+/// Return the pipelines rules.
+#[get("/pipelines")]
+async fn pipelines(_user : Viewer, pipelines : &State<Arc<Pipelines>>) -> Json<Vec<PipelineRule>>
+{
+  Json(pipelines.rules())
+}
+
+/// Add a pipeline rule.
+#[post("/pipelines", data = "<rule>", format = "json")]
+async fn pipeline_create(user : Analyst, pipelines : &State<Arc<Pipelines>>, audit_log : &State<AuditLog>, rule : Json<PipelineRule>) -> Result<(), ApiError>
+{
+  let arguments = json!(&*rule);
+  let result = pipelines.add(rule.into_inner());
+  audit_log.record(&user.0, "pipeline_create", arguments, AuditOutcome::from_result(&result)).await;
+
+  result.map_err(ApiError::from)
+}
+
+/// Replace the pipeline rule `name`.
+#[put("/pipelines/<name>", data = "<rule>", format = "json")]
+async fn pipeline_update(user : Analyst, pipelines : &State<Arc<Pipelines>>, audit_log : &State<AuditLog>, name : &str, rule : Json<PipelineRule>) -> Result<(), ApiError>
+{
+  let arguments = json!({"name" : name, "rule" : &*rule});
+  let result = pipelines.replace(name, rule.into_inner());
+  audit_log.record(&user.0, "pipeline_update", arguments, AuditOutcome::from_result(&result)).await;
+
+  result.map_err(ApiError::from)
+}
+
+/// Remove the pipeline rule `name`.
+#[delete("/pipelines/<name>")]
+async fn pipeline_delete(user : Analyst, pipelines : &State<Arc<Pipelines>>, audit_log : &State<AuditLog>, name : &str) -> Result<(), ApiError>
+{
+  let result = pipelines.remove(name);
+  audit_log.record(&user.0, "pipeline_delete", json!({"name" : name}), AuditOutcome::from_result(&result)).await;
+
+  result.map_err(ApiError::from)
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, JsonSchema)]
 struct AttributeInfo
 {
+  #[schemars(with = "NodeId")]
   node_id : TreeNodeId,
   name : String,    
   //value : Value, 
+  #[schemars(with = "Value")]
   value : tap::value::Value, 
   description : Option<String>,
 }
@@ -423,10 +715,13 @@ struct AttributeInfo
 
 /// Add an attribute to a node (don't support dotted notation yet).
 #[post("/attribute", data = "<attribute>", format = "json")]
-async fn attribute(_key : ApiKey<'_>, session : &State<ArcSession>, attribute : Json<AttributeInfo>) -> Option<()> 
+async fn attribute(user : Analyst, session : &State<ArcSession>, audit_log : &State<AuditLog>, events : &State<Events>, query_cache : &State<Arc<QueryCache>>, attribute : Json<AttributeInfo>) -> Result<(), ApiError>
 {
   let session = session.inner().clone();
-  rocket::tokio::task::spawn_blocking(move || {
+  let arguments = json!(&*attribute);
+  let (node_id, name) = (attribute.node_id, attribute.name.clone());
+
+  let result = rocket::tokio::task::spawn_blocking(move || {
   let node = session.tree.get_node_from_id(attribute.node_id)?; 
 
   match &attribute.description
@@ -434,75 +729,394 @@ async fn attribute(_key : ApiKey<'_>, session : &State<ArcSession>, attribute :
     Some(descr) => node.value().add_attribute(attribute.name.clone(), attribute.value.clone(), Some(descr.clone())),
     None => node.value().add_attribute(attribute.name.clone(), attribute.value.clone(), None),
   }
-  Some(())
-  }).await.unwrap()
+  session.tree.node_path(attribute.node_id)
+  }).await?.ok_or_else(ApiError::node_not_found);
+  query_cache.invalidate();
+  audit_log.record(&user.0, "attribute", arguments, AuditOutcome::from_result(&result.as_ref().map(|_| ()))).await;
 
+  events.send(Event::Attribute{ node_id, path : result?, name });
+  Ok(())
 }
 
-#[derive(Deserialize, Debug)]
+/// Stream task state changes and tree modifications as server-sent events, 
+/// optionally only for one task or for the nodes of a subtree.
+#[get("/events?<apikey>&<task_id>&<root>")]
+fn events(user : Option<Viewer>, users : &State<Users>, events : &State<Events>, mut shutdown : Shutdown, 
+          apikey : Option<&str>, task_id : Option<u32>, root : Option<String>) -> Result<EventStream![], ApiError>
+{
+  //EventSource can't set headers, so the key can also be passed in the url like for download_id
+  if user.is_none() && apikey.and_then(|key| users.authenticate(key)).is_none()
+  {
+    return Err(ApiError::unauthorized())
+  }
+
+  let mut receiver = events.subscribe();
+  let filter = EventFilter{ task_id, root };
+
+  Ok(EventStream!{
+    loop 
+    {
+      let event = select!{
+        event = receiver.recv() => match event 
+        {
+          Ok(event) => event,
+          Err(RecvError::Closed) => break,
+          //the client is too slow, the oldest events are skipped
+          Err(RecvError::Lagged(_)) => continue,
+        },
+        _ = &mut shutdown => break,
+      };
+
+      if let Some(event) = filter.select(&event)
+      {
+        yield SseEvent::json(&event).event(event.name());
+      }
+    }
+  })
+}
+
+/// Nodes returned with the results of a query.
+#[derive(Deserialize, Debug, Default, JsonSchema)]
+pub struct QueryOutput
+{
+  /// Sort and page of the results to return, all the results are returned as a list if not set.
+  #[serde(flatten)]
+  pub page : PageRequest,
+  /// Return the nodes with these fields instead of their id.
+  pub option : Option<NodeOption>,
+  /// Return the nodes with only these attributes instead of their id.
+  pub attributes : Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
 pub struct QueryInfo 
 {
   pub query : String,
   pub root : String, 
+  #[serde(flatten)]
+  pub output : QueryOutput,
 }
 
-/// Execute a query and return a node list.
-#[post("/query", data = "<query_info>", format = "json")] 
-async fn query(_key : ApiKey<'_>, session : &State<ArcSession>, query_info : Json<QueryInfo>) -> Result<Json<Vec<TreeNodeId>>, BadRequest<String>>
+/// Return the nodes matching a query, they are kept in `cache` until the tree is modified.
+fn query_nodes(session : &Session, cache : &QueryCache, query : &str, path : &str) -> Result<Arc<Vec<TreeNodeId>>, ApiError>
 {
+  let generation = match cache.get(query, path)
+  {
+    Ok(nodes_id) => return Ok(nodes_id),
+    Err(generation) => generation,
+  };
+
   //XXX use filter_path or  filter_nodes
   //XXX check if path is "" or "/" -> search for "/root"
-  let session = session.inner().clone();
-
-  rocket::tokio::task::spawn_blocking(move || {
-  let path  = &query_info.root;
-  let query = &query_info.query;
   info!("executing query {} on {}", query, path);
+  let nodes_id = match Filter::path(&session.tree, query, path)
+  {
+    Ok(res) => Arc::new(res),
+    Err(err) => return Err(ApiError::bad_request("invalid_query", err.to_string())),
+  };
+  cache.insert(generation, query, path, nodes_id.clone());
+  Ok(nodes_id)
+}
 
-  match Filter::path(&session.tree, query, path)
+/// Return the nodes of `query` sorted by `sort`, they are kept in `cache` with the nodes so the next pages are not sorted again.
+fn sort_nodes(session : &Session, cache : &QueryCache, query : &str, path : &str, nodes_id : &Arc<Vec<TreeNodeId>>, sort : &str, order : Order) -> Arc<SortedNodes>
+{
+  if let Some(sorted) = cache.get_sorted(query, path, nodes_id, sort, order)
   {
-    Ok(res) => Ok(Json(res)),
-    Err(err) => Err(BadRequest(Some(err.to_string()))),
+    return sorted
   }
-  }).await.unwrap()
+
+  let page = PageRequest{ sort : Some(sort.to_string()), order, ..Default::default() };
+  let sorted = Arc::new(page.sort_nodes(session, nodes_id));
+  cache.insert_sorted(query, path, nodes_id, sort, order, sorted.clone());
+  sorted
 }
 
-/// Upload a file to the server to be processed later.
-#[post("/upload?<name>", data = "<data>")]
-async fn upload(_key : ApiKey<'_>, upload_dir : &State<String>, name : String, data : Data<'_>) -> Result<Json<u64>, BadRequest<String>>
+/// Execute a query and serialize the results as requested by `output`.
+fn run_query(session : &Session, cache : &QueryCache, query : &str, path : &str, output : &QueryOutput) -> Result<(ContentType, Vec<u8>), ApiError>
 {
-  let file_path = upload_dir.to_string() + "/" + &name;
-  info!("Uploading file to : {}", file_path);
-
-  let file = data.open(4096.gibibytes()).into_file(file_path).await;
-  let file = match file
+  let nodes_id = query_nodes(session, cache, query, path)?;
+  let nodes_id = match &output.page.sort
   {
-    Ok(file) => file,
-    Err(err) => return Err(BadRequest(Some(err.to_string()))),
+    Some(sort) => output.page.page(&sort_nodes(session, cache, query, path, &nodes_id, sort, output.page.order))?,
+    None => output.page.apply(session, &nodes_id)?,
   };
-  if !file.is_complete() 
+
+  let mut ser = serde_json::Serializer::new(Vec::new());
+  match (&output.option, &output.attributes)
   {
-    return Err(BadRequest(Some("File uploaded is not complete".to_string())));
+    (None, None) => nodes_id.serialize(&mut ser)?,
+    (option, attributes) => nodes_id.serialize_with(&mut ser, |nodes_id| 
+      NodesJson{ session, nodes_id, option : option.as_ref(), attributes : attributes.as_deref() })?,
   }
+  Ok((ContentType::JSON, ser.into_inner()))
+}
 
-  Ok(Json(file.n.written as u64))
+/// Execute a query and return a node list, or a sorted page of the nodes with the total count.
+/// Nodes are returned with their fields if `option` or `attributes` are set.
+#[post("/query", data = "<query_info>", format = "json")] 
+async fn query(_user : Viewer, session : &State<ArcSession>, query_cache : &State<Arc<QueryCache>>, query_info : Json<QueryInfo>) -> Result<(ContentType, Vec<u8>), ApiError>
+{
+  let session = session.inner().clone();
+  let query_cache = query_cache.inner().clone();
+
+  spawn_thread!(run_query(&session, &query_cache, &query_info.query, &query_info.root, &query_info.output))
 }
 
-/// Download a node data attribute.
-#[post("/download", data = "<node_id>", format = "json")]
-async fn download(_key : ApiKey<'_>, session : &State<ArcSession>, node_id : Json<TreeNodeId>) -> Result<AsyncVFile, BadRequest<String>>
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct AggregateInfo
+{
+  pub query : String,
+  pub root : String,
+  /// `name`, `extension`, `size` or the path of an attribute.
+  pub attribute : String,
+  pub bucket : Bucket,
+}
+
+/// Count the nodes returned by a query grouped by the value of an attribute.
+#[post("/aggregate", data = "<info>", format = "json")]
+async fn aggregate(_user : Viewer, session : &State<ArcSession>, query_cache : &State<Arc<QueryCache>>, info : Json<AggregateInfo>) -> Result<Json<Aggregation>, ApiError>
 {
   let session = session.inner().clone();
-  rocket::tokio::task::spawn_blocking(move || {
+  let query_cache = query_cache.inner().clone();
+
+  spawn_thread!({
+    let nodes_id = query_nodes(&session, &query_cache, &info.query, &info.root)?;
+    aggregate_nodes(&session, &nodes_id, &info.attribute, &info.bucket).map(Json)
+  })
+}
+
+/// Return the saved queries and the queries of the built-in library.
+#[get("/queries")]
+async fn saved_queries(_user : Viewer, queries : &State<SavedQueries>) -> Json<Vec<SavedQuery>>
+{
+  Json(queries.list())
+}
+
+/// Save a query under a name.
+#[post("/queries", data = "<saved_query>", format = "json")]
+async fn saved_query_create(user : Analyst, queries : &State<SavedQueries>, audit_log : &State<AuditLog>, saved_query : Json<SavedQuery>) -> Result<(), ApiError>
+{
+  let arguments = json!(&*saved_query);
+  let result = queries.add(saved_query.into_inner());
+  audit_log.record(&user.0, "saved_query_create", arguments, AuditOutcome::from_result(&result)).await;
+
+  result.map_err(ApiError::from)
+}
+
+/// Replace the saved query `name`.
+#[put("/queries/<name>", data = "<saved_query>", format = "json")]
+async fn saved_query_update(user : Analyst, queries : &State<SavedQueries>, audit_log : &State<AuditLog>, name : &str, saved_query : Json<SavedQuery>) -> Result<(), ApiError>
+{
+  let arguments = json!({"name" : name, "query" : &*saved_query});
+  let result = queries.replace(name, saved_query.into_inner());
+  audit_log.record(&user.0, "saved_query_update", arguments, AuditOutcome::from_result(&result)).await;
+
+  result.map_err(ApiError::from)
+}
+
+/// Remove the saved query `name`.
+#[delete("/queries/<name>")]
+async fn saved_query_delete(user : Analyst, queries : &State<SavedQueries>, audit_log : &State<AuditLog>, name : &str) -> Result<(), ApiError>
+{
+  let result = queries.remove(name);
+  audit_log.record(&user.0, "saved_query_delete", json!({"name" : name}), AuditOutcome::from_result(&result)).await;
+
+  result.map_err(ApiError::from)
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct RunQueryInfo
+{
+  /// Execute the query on this node instead of the root saved with the query.
+  pub root : Option<String>,
+  #[serde(flatten)]
+  pub output : QueryOutput,
+}
+
+/// Execute the saved query `name`, results are returned like for `/query`.
+#[post("/queries/<name>/run", data = "<info>", format = "json")]
+async fn saved_query_run(_user : Viewer, session : &State<ArcSession>, queries : &State<SavedQueries>, query_cache : &State<Arc<QueryCache>>, name : &str, info : Json<RunQueryInfo>) -> Result<(ContentType, Vec<u8>), ApiError>
+{
+  let saved = queries.get(name)?;
+  let session = session.inner().clone();
+  let query_cache = query_cache.inner().clone();
+
+  spawn_thread!({
+    let root = info.root.as_deref().unwrap_or(&saved.root);
+    run_query(&session, &query_cache, &saved.query, root, &info.output)
+  })
+}
 
-  let node = session.tree.get_node_from_id(*node_id).ok_or_else(|| BadRequest(Some("Invalid NodeId".to_string())))?;
-  let attr = node.value().get_value("data").ok_or_else(|| BadRequest(Some("No data attribute on node".to_string())))?;
+/// Upload a file to the server to be processed later, 
+/// return the path where it was stored and it's hashes, that are checked against the optional expected hashes.
+#[post("/upload?<name>&<conflict>&<md5>&<sha1>&<sha256>", data = "<data>")]
+async fn upload(user : Analyst, upload_dir : &State<String>, audit_log : &State<AuditLog>, name : String, conflict : Option<Conflict>, 
+                md5 : Option<String>, sha1 : Option<String>, sha256 : Option<String>, data : Data<'_>) -> Result<Json<UploadInfo>, ApiError>
+{
+  let conflict = conflict.unwrap_or_default();
+  let expected = ExpectedHashes{ md5, sha1, sha256 };
+  info!("Uploading file {} to : {}", name, upload_dir.inner());
+
+  let result = store_upload(Path::new(upload_dir.as_str()), &name, conflict, &expected, &user.0.name, data).await;
+  audit_log.record(&user.0, "upload", json!({"name" : name, "conflict" : conflict, "expected" : expected}), AuditOutcome::from_result(&result)).await;
+
+  result.map(Json).map_err(ApiError::from)
+}
+
+/// Return the manifest of the files uploaded to the server.
+#[get("/uploads")]
+async fn uploads(_user : Viewer, upload_dir : &State<String>) -> Result<Json<Vec<UploadInfo>>, ApiError>
+{
+  let upload_dir = upload_dir.inner().clone();
+  spawn_thread!(list_uploads(Path::new(&upload_dir))).map(Json).map_err(ApiError::from)
+}
+
+/// Create a resumable upload session for a file of a known size.
+#[post("/upload/session", data = "<new_session>", format = "json")]
+async fn upload_session_create(user : Analyst, sessions : &State<Arc<UploadSessions>>, upload_dir : &State<String>, audit_log : &State<AuditLog>, new_session : Json<NewUploadSession>) -> Result<Json<UploadSession>, ApiError>
+{
+  let arguments = json!({"name" : new_session.name, "size" : new_session.size, "expected" : new_session.expected});
+
+  let sessions = sessions.inner().clone();
+  let upload_dir = upload_dir.inner().clone();
+  let name = user.0.name.clone();
+  let result = spawn_thread!(sessions.create(&name, new_session.into_inner(), Path::new(&upload_dir)));
+  audit_log.record(&user.0, "upload_session_create", arguments, AuditOutcome::from_result(&result.as_ref().map(|session| &session.id))).await;
+
+  result.map(Json).map_err(ApiError::from)
+}
+
+/// Return the upload sessions in progress.
+#[get("/upload/sessions")]
+async fn upload_sessions(_user : Analyst, sessions : &State<Arc<UploadSessions>>) -> Json<Vec<UploadSession>>
+{
+  Json(sessions.list())
+}
+
+/// Return an upload session with the ranges received so far.
+#[get("/upload/session/<id>")]
+async fn upload_session(_user : Analyst, sessions : &State<Arc<UploadSessions>>, id : &str) -> Result<Json<UploadSession>, ApiError>
+{
+  sessions.get(id).map(Json).map_err(ApiError::from)
+}
+
+/// Write a chunk of data at `offset` in an upload session.
+#[put("/upload/session/<id>?<offset>", data = "<data>")]
+async fn upload_session_chunk(_user : Analyst, sessions : &State<Arc<UploadSessions>>, id : &str, offset : u64, data : Data<'_>) -> Result<Json<UploadSession>, ApiError>
+{
+  sessions.write_chunk(id, offset, data).await.map(Json)
+}
+
+/// Check that the file is complete and match the expected hash, then move it to the upload directory.
+#[post("/upload/session/<id>/finalize")]
+async fn upload_session_finalize(user : Analyst, sessions : &State<Arc<UploadSessions>>, upload_dir : &State<String>, audit_log : &State<AuditLog>, id : String) -> Result<Json<UploadInfo>, ApiError>
+{
+  let sessions = sessions.inner().clone();
+  let upload_dir = upload_dir.inner().clone();
+  let arguments = json!({"id" : id});
+
+  let result = spawn_thread!(sessions.finalize(&id, Path::new(&upload_dir)));
+  audit_log.record(&user.0, "upload_session_finalize", arguments, AuditOutcome::from_result(&result)).await;
+
+  result.map(Json)
+}
+
+/// Abort an upload session and remove the data received so far.
+#[delete("/upload/session/<id>")]
+async fn upload_session_delete(user : Analyst, sessions : &State<Arc<UploadSessions>>, audit_log : &State<AuditLog>, id : &str) -> Result<(), ApiError>
+{
+  let result = sessions.remove(id);
+  audit_log.record(&user.0, "upload_session_delete", json!({"id" : id}), AuditOutcome::from_result(&result)).await;
+
+  result
+}
+
+/// Error returned for a node without data.
+fn no_data() -> ApiError
+{
+  ApiError::not_found("no_data", "No data attribute on node")
+}
+
+/// Error returned when the data of a node can't be opened.
+fn read_failed<E : Display>(err : E) -> ApiError
+{
+  ApiError::unprocessable("read_failed", err.to_string())
+}
+
+/// Return the etag used to check that a node data didn't change between two range requests.
+fn node_etag(node_id : TreeNodeId, size : u64) -> String
+{
+  let node_id = json!(node_id);
+  format!("\"{}-{}-{}\"", node_id["index1"], node_id["stamp"], size)
+}
+
+/// Open a node data attribute as a stream to be downloaded, limited to the requested ranges.
+fn open_data(session : &Session, node_id : TreeNodeId, range : &RangeHeaders) -> Result<AsyncVFile, ApiError>
+{
+  let node = session.tree.get_node_from_id(node_id).ok_or_else(ApiError::node_not_found)?;
+  let attr = node.value().get_value("data").ok_or_else(no_data)?;
   let builder = attr.as_vfile_builder();
-  let file = builder.open().map_err(|err| BadRequest(Some(err.to_string())))?;
-  let stream = AsyncVFile::new(Box::new(file), Some((node.name(), builder.size())));
+  let name = node.name();
+  let size = builder.size();
+  let etag = node_etag(node_id, size);
 
-  Ok(stream) 
-  }).await.unwrap()
+  let open_range = |start : u64, end : u64| -> Result<_, ApiError>
+  {
+    let mut file = builder.open().map_err(read_failed)?;
+    file.seek(SeekFrom::Start(start))?;
+    Ok(file.take(end - start + 1))
+  };
+
+  match range.ranges(size, &etag)
+  {
+    ByteRanges::Full => 
+    {
+      let file = builder.open().map_err(read_failed)?;
+      Ok(AsyncVFile::with_content(Box::new(file), Content::File{ name, size, etag : Some(etag) }))
+    },
+    ByteRanges::Single(start, end) => 
+    {
+      let file = open_range(start, end)?;
+      Ok(AsyncVFile::with_content(Box::new(file), Content::Range{ name, start, end, size, etag }))
+    },
+    ByteRanges::Multiple(ranges) =>
+    {
+      let boundary = Uuid::new_v4().simple().to_string();
+      let mut body : Box<dyn Read + Sync + Send> = Box::new(io::empty());
+      let mut length = 0;
+
+      for (index, (start, end)) in ranges.into_iter().enumerate()
+      {
+        let separator = if index == 0 { "" } else { "\r\n" };
+        let header = format!("{}--{}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", 
+                             separator, boundary, start, end, size);
+        length += header.len() as u64 + end - start + 1;
+        body = Box::new(body.chain(io::Cursor::new(header)).chain(open_range(start, end)?));
+      }
+
+      let trailer = format!("\r\n--{}--\r\n", boundary);
+      length += trailer.len() as u64;
+      body = Box::new(body.chain(io::Cursor::new(trailer)));
+
+      Ok(AsyncVFile::with_content(body, Content::MultiRange{ name, boundary, length, etag }))
+    },
+    ByteRanges::Unsatisfiable => Ok(AsyncVFile::with_content(Box::new(io::empty()), Content::Unsatisfiable{ size })),
+  }
+}
+
+/// Download a node data attribute, support the `Range` and `If-Range` headers.
+#[post("/download", data = "<node_id>", format = "json")]
+async fn download(user : Viewer, session : &State<ArcSession>, audit_log : &State<AuditLog>, range : RangeHeaders, node_id : Json<TreeNodeId>) -> Result<AsyncVFile, ApiError>
+{
+  let node_id : TreeNodeId = *node_id;
+  let arguments = json!({"node_id" : node_id, "range" : range.range});
+
+  let session = session.inner().clone();
+  let result = spawn_thread!(open_data(&session, node_id, &range));
+  audit_log.record(&user.0, "download", arguments, AuditOutcome::from_result(&result.as_ref().map(|_| ()))).await;
+
+  result
 }
 
 #[derive(Debug, PartialEq, FromForm)]
@@ -512,102 +1126,215 @@ struct FromNodeId
   stamp: usize,
 }
 
+/// Download a node data attribute from a link, support the `Range` and `If-Range` headers.
 #[get("/download_id?<apikey>&<node_id>")] 
-async fn download_id(session : &State<ArcSession>, api_key : &State<ConfigApiKey>, apikey : &'_ str, node_id : FromNodeId) -> Result<AsyncVFile, Custom<String>>
+async fn download_id(session : &State<ArcSession>, users : &State<Users>, audit_log : &State<AuditLog>, range : RangeHeaders, apikey : &'_ str, node_id : FromNodeId) -> Result<AsyncVFile, ApiError>
 {
-  if api_key.inner().0 != apikey
+  let user = match users.authenticate(apikey)
   {
-    return Err(Custom(Status::Unauthorized, "Invalid API key".into()))
-  }
+    Some(user) => user.clone(),
+    None => return Err(ApiError::unauthorized()),
+  };
+
+  let node_id_str = json!({"index1":  node_id.index1, "stamp" : node_id.stamp}).to_string();
+  let node_id : TreeNodeId = serde_json::from_str(&node_id_str).map_err(|err| ApiError::bad_request("invalid_node_id", err.to_string()))?;
+
+  let arguments = json!({"node_id" : node_id, "range" : range.range});
 
   let session = session.inner().clone();
-  rocket::tokio::task::spawn_blocking(move || {
+  let result = spawn_thread!(open_data(&session, node_id, &range));
+  audit_log.record(&user, "download_id", arguments, AuditOutcome::from_result(&result.as_ref().map(|_| ()))).await;
 
-  let node_id_str = json!({"index1":  node_id.index1, "stamp" : node_id.stamp}).to_string();
-  let node_id : TreeNodeId = serde_json::from_str(&node_id_str).unwrap();
-  let node = session.tree.get_node_from_id(node_id).ok_or_else(|| Custom(Status::BadRequest, "Invalid NodeId".into()))?;
-  let attr = node.value().get_value("data").ok_or_else(|| Custom(Status::BadRequest, "No data attribute on node".into()))?;
-  let builder = attr.as_vfile_builder();
-  let file = builder.open().map_err(|err| Custom(Status::BadRequest, err.to_string()))?;
-  let stream = AsyncVFile::new(Box::new(file), Some((node.name(), builder.size())));
-  
-  Ok(stream) 
-  }).await.unwrap()
+  result
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ExportInfo
+{
+  /// Nodes to export, or a query and the root it's executed on like for `/query`.
+  #[schemars(with = "Option<Vec<NodeId>>")]
+  pub nodes_id : Option<Vec<TreeNodeId>>,
+  pub query : Option<String>,
+  pub root : Option<String>,
+  pub format : ArchiveFormat,
+}
+
+/// Export the data of a list of nodes, or of the nodes returned by a query, in a zip or tar archive.
+#[post("/export", data = "<export_info>", format = "json")]
+async fn export(user : Viewer, session : &State<ArcSession>, audit_log : &State<AuditLog>, export_info : Json<ExportInfo>) -> Result<AsyncVFile, ApiError>
+{
+  let session = session.inner().clone();
+  let export_info = export_info.into_inner();
+  let format = export_info.format;
+  let mut arguments = json!({"query" : export_info.query, "root" : export_info.root, "format" : format});
+
+  let nodes_id = match (export_info.nodes_id, export_info.query)
+  {
+    (Some(nodes_id), None) => Ok(nodes_id),
+    (None, Some(query)) =>
+    {
+      let root = export_info.root.unwrap_or_else(|| "/root".into());
+      let tree_session = session.clone();
+      spawn_thread!(Filter::path(&tree_session.tree, &query, &root).map_err(|err| ApiError::bad_request("invalid_query", err.to_string())))
+    },
+    _ => Err(ApiError::bad_request("bad_request", "Either nodes_id or query must be provided")),
+  };
+
+  arguments["nodes_count"] = json!(nodes_id.as_ref().map(|nodes_id| nodes_id.len()).ok());
+  audit_log.record(&user.0, "export", arguments, AuditOutcome::from_result(&nodes_id.as_ref().map(|_| ()))).await;
+
+  Ok(export_nodes(session, nodes_id?, format))
 }
 
-#[derive(Deserialize)]
-pub struct ReadInfo 
+#[derive(Deserialize, JsonSchema)]
+pub struct ReadInfo
 {
+  #[schemars(with = "NodeId")]
   pub node_id : TreeNodeId,
   pub offset : u64,
   pub size : u64, 
 }
 
-/// Read from a node data attribute.
-#[post("/read", data = "<data>", format = "json")]
-async fn read(_key : ApiKey<'_>,  session : &State<ArcSession>, data : Json<ReadInfo>) -> Result<AsyncVFile, BadRequest<String>>
+fn read_data(session : &Session, data : &ReadInfo) -> Result<AsyncVFile, ApiError>
 {
-  let node = session.tree.get_node_from_id(data.node_id).ok_or_else(|| BadRequest(Some("Invalid NodeId".to_string())))?;
+  let node = session.tree.get_node_from_id(data.node_id).ok_or_else(ApiError::node_not_found)?;
 
-  let attr = node.value().get_value("data").ok_or_else(|| BadRequest(Some("No data attribute on node".to_string())))?;
+  let attr = node.value().get_value("data").ok_or_else(no_data)?;
   let builder = attr.as_vfile_builder();
-  let mut file = builder.open().map_err(|err| BadRequest(Some(err.to_string())))?;
+  let mut file = builder.open().map_err(read_failed)?;
 
   if data.offset != 0 {
-    file.seek(SeekFrom::Start(data.offset)).map_err(|err| BadRequest(Some(err.to_string())))?;  }
+    file.seek(SeekFrom::Start(data.offset))?;  }
 
   let handler = file.take(data.size);
   
   Ok(AsyncVFile::new(Box::new(handler), None))
 }
 
-#[derive(Deserialize)]
+/// Read from a node data attribute.
+#[post("/read", data = "<data>", format = "json")]
+async fn read(user : Viewer,  session : &State<ArcSession>, audit_log : &State<AuditLog>, data : Json<ReadInfo>) -> Result<AsyncVFile, ApiError>
+{
+  let data = data.into_inner();
+  let arguments = json!({"node_id" : data.node_id, "offset" : data.offset, "size" : data.size});
+
+  let session = session.inner().clone();
+  let result = spawn_thread!(read_data(&session, &data));
+  audit_log.record(&user.0, "read", arguments, AuditOutcome::from_result(&result.as_ref().map(|_| ()))).await;
+
+  result
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
 struct SaveFile
 {
   pub file_name : String,
 }
 
-/// Save server task list.
+/// Save server task list, and the saved queries in a `<file_name>.queries.json` file next to it.
 #[post("/save", data = "<data>", format = "json")]
-async fn save(_key : ApiKey<'_>,  session : &State<ArcSession>, data : Json<SaveFile>) -> Option<()> 
+async fn save(user : Analyst,  session : &State<ArcSession>, queries : &State<SavedQueries>, audit_log : &State<AuditLog>, data : Json<SaveFile>) -> Result<(), ApiError>
 {
   let session = session.inner().clone();
   let saver = Save::Replay;
+  let arguments = json!(&*data);
+  let queries_file = queries_path(&data.file_name);
   
-  spawn_thread!(saver.to_file(data.file_name.clone(), &session));
-  Some(())
+  let result = spawn_thread!(saver.to_file(data.file_name.clone(), &session))
+                 .map(|_| ()).map_err(|err| ApiError::bad_request("save_failed", err.to_string()))
+                 .and_then(|_| Ok(queries.save(&queries_file)?));
+  audit_log.record(&user.0, "save", arguments, AuditOutcome::from_result(&result)).await;
+  result
+}
+
+/// Files read by `/load`.
+#[derive(Serialize, JsonSchema)]
+struct LoadResult
+{
+  /// File of the saved queries, it's expected next to the case file.
+  queries_file : String,
+  /// False if the queries file wasn't found, the current saved queries are then kept.
+  queries_loaded : bool,
+  /// Queries of the file that were not loaded because their name is already used by a built-in query or by another query of the file.
+  queries_skipped : Vec<String>,
 }
 
-/// Load server task list.
+/// Load server task list, and the saved queries if they were saved with it.
 #[post("/load", data = "<data>", format = "json")]
-async fn load(_key : ApiKey<'_>,  session : &State<ArcSession>, data : Json<SaveFile>) -> Option<()> 
+async fn load(user : Admin,  session : &State<ArcSession>, queries : &State<SavedQueries>, query_cache : &State<Arc<QueryCache>>, audit_log : &State<AuditLog>, 
+              evidence_roots : &State<Arc<EvidenceRoots>>, data : Json<SaveFile>) -> Result<Json<LoadResult>, ApiError>
 {
   let session = session.inner().clone();
   let loader = Save::Replay;
+  let arguments = json!(&*data);
+  let queries_file = queries_path(&data.file_name);
+
+  //replayed tasks must not read files outside of the evidence directories
+  if let Err(err) = evidence_roots.check_saved_tasks(&data.file_name)
+  {
+    audit_log.record(&user.0, "load", arguments, AuditOutcome::error(&err)).await;
+    return Err(err.into())
+  }
+
+  let update = query_cache.update();
+  let result = spawn_thread!(loader.from_file(data.file_name.clone(), &session))
+                 .map(|_| ()).map_err(|err| ApiError::bad_request("load_failed", err.to_string()))
+                 .and_then(|_| Ok(queries.load(&queries_file)?))
+                 .map(|skipped| LoadResult{ queries_file : queries_file.display().to_string(), queries_loaded : skipped.is_some(), queries_skipped : skipped.unwrap_or_default() });
+  drop(update);
+  if let Ok(LoadResult{ queries_loaded : false, .. }) = &result
+  {
+    warn!("Saved queries file {} not found, the current saved queries are kept", queries_file.display());
+  }
+  audit_log.record(&user.0, "load", arguments, AuditOutcome::from_result(&result)).await;
+  result.map(Json)
+}
+
+/// Parse an optional rfc3339 date passed as a query parameter.
+fn parse_date(date : Option<String>) -> Result<Option<DateTime<Utc>>, ApiError>
+{
+  date.map(|date| rfc3339_date(&date)).transpose()
+}
+
+/// Parse an rfc3339 date and convert it to utc.
+fn rfc3339_date(date : &str) -> Result<DateTime<Utc>, ApiError>
+{
+  match DateTime::parse_from_rfc3339(date)
+  {
+    Ok(date) => Ok(date.with_timezone(&Utc)),
+    Err(err) => Err(ApiError::bad_request("invalid_date", format!("Invalid date {} : {}", date, err))),
+  }
+}
+
+/// Return the audit log entries, optionally filtered by user, route and time.
+#[get("/audit?<user>&<route>&<after>&<before>")]
+async fn audit(_user : Analyst, audit_log : &State<AuditLog>, user : Option<String>, route : Option<String>, 
+               after : Option<String>, before : Option<String>) -> Result<Json<Vec<AuditEntry>>, ApiError>
+{
+  let filter = AuditFilter{ user, route, after : parse_date(after)?, before : parse_date(before)? };
 
-  spawn_thread!(loader.from_file(data.file_name.clone(), &session));
-  Some(())
+  Ok(Json(audit_log.entries(filter).await?))
 }
 
 /// Return total node count in the tree.
 #[get("/node_count")]
-async fn node_count(_key : ApiKey<'_>,  session : &State<ArcSession>) -> Json<usize>
+async fn node_count(_user : Viewer,  session : &State<ArcSession>) -> Result<Json<usize>, ApiError>
 {
   let session = session.inner().clone();
 
-  spawn_thread!(Json(session.tree.count()))
+  Ok(spawn_thread!(Json(session.tree.count())))
 }
 
 /// Return total attribute count in the tree.
 #[get("/attribute_count")]
-async fn attribute_count(_key : ApiKey<'_>,   session : &State<ArcSession>) -> Json<u64>
+async fn attribute_count(_user : Viewer,   session : &State<ArcSession>) -> Result<Json<u64>, ApiError>
 {
   let session = session.inner().clone();
 
-  spawn_thread!(Json(query_attribute_count(&session.tree)))
+  Ok(spawn_thread!(Json(query_attribute_count(&session.tree))))
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 pub struct TimeRange 
 {
   after : String,
@@ -617,27 +1344,18 @@ pub struct TimeRange
 
 /// Create a timeline from the attributes.
 #[post("/timeline", data = "<time_range>", format = "json")]
-async fn timeline(_key : ApiKey<'_>,  session : &State<ArcSession>, time_range : Json<TimeRange>) -> Result<Vec<u8>, BadRequest<String>>
+async fn timeline(_user : Viewer,  session : &State<ArcSession>, time_range : Json<TimeRange>) -> Result<Vec<u8>, ApiError>
 {
-  let after = match DateTime::parse_from_rfc3339(&time_range.after)
-  {
-    Ok(after) => after.with_timezone(&Utc),
-    Err(err) => return Err(BadRequest(Some(err.to_string()))),
-  };
-
-  let before = match DateTime::parse_from_rfc3339(&time_range.before)
-  {
-    Ok(before) => before.with_timezone(&Utc),
-    Err(err) => return Err(BadRequest(Some(err.to_string()))),
-  };
+  let after = rfc3339_date(&time_range.after)?;
+  let before = rfc3339_date(&time_range.before)?;
 
   let session = session.inner().clone();
 
-  rocket::tokio::task::spawn_blocking(move || {
+  rocket::tokio::task::spawn_blocking(move || -> Result<Vec<u8>, ApiError> {
     let time_infos = query_timeline::Timeline::tree(&session.tree, &after, &before);
   
     let mut ser = serde_json::Serializer::new(Vec::new());   
-    let mut seq = ser.serialize_seq(None).unwrap();
+    let mut seq = ser.serialize_seq(None)?;
     for time_info in time_infos
     {
       let mut time_info_json = json!({"id" : time_info.id, 
@@ -650,11 +1368,138 @@ async fn timeline(_key : ApiKey<'_>,  session : &State<ArcSession>, time_range :
          time_info_json.merge(option_json);
       }
 
-      seq.serialize_element(&time_info_json).unwrap();
+      seq.serialize_element(&time_info_json)?;
     }
-    seq.end().unwrap();
+    seq.end()?;
     Ok(ser.into_inner())
-  }).await.unwrap()
+  }).await?
+}
+
+/// Return the OpenAPI document of the API.
+#[get("/openapi.json")]
+fn openapi_json(document : &State<ApiDocument>) -> Json<&Value>
+{
+  Json(&document.0)
+}
+
+/// Show the OpenAPI document with Swagger UI.
+#[cfg(feature = "api-docs")]
+#[get("/docs")]
+fn api_docs() -> RawHtml<&'static str>
+{
+  RawHtml(crate::openapi::SWAGGER_UI)
+}
+
+/// Return the Swagger UI files embedded in the server.
+#[cfg(feature = "api-docs")]
+#[get("/docs/<file>")]
+fn api_docs_file(file : &str) -> Option<(ContentType, &'static [u8])>
+{
+  let file = crate::openapi::SWAGGER_UI_DIR.get_file(file)?;
+  let content_type = file.path().extension().and_then(|extension| extension.to_str())
+                         .and_then(ContentType::from_extension).unwrap_or(ContentType::Binary);
+  Some((content_type, file.contents()))
+}
+
+/// Return the request and response body of a route, used to generate the OpenAPI document.
+/// Every route mounted under `/api` must be listed, the server doesn't start otherwise.
+fn route_bodies(route : &str, gen : &mut SchemaGenerator) -> Option<(Body, Body)>
+{
+  use Body::{Empty, Binary, Text, Html};
+
+  let bodies = match route
+  {
+    "plugins" => (Empty, Body::json::<Vec<PluginInfo>>(gen)),
+    "plugin" => (Empty, Body::json::<PluginInfo>(gen)),
+    "root" | "node_by_path" => (Empty, Body::json::<Value>(gen)),
+    "node" => (Body::json::<NodeIdOption>(gen), Body::json::<Value>(gen)),
+    "nodes" => (Body::json::<NodesIdOption>(gen), Body::json::<Vec<Value>>(gen)),
+    "delete" => (Body::json::<NodeId>(gen), Empty),
+    "path" => (Body::json::<NodeId>(gen), Text),
+    "parent_id" => (Body::json::<NodeId>(gen), Body::json::<Option<NodeId>>(gen)),
+    "run" => (Body::json::<PluginArgs>(gen), Body::json::<Value>(gen)),
+    "schedule" => (Body::json::<PluginArgs>(gen), Body::json::<u32>(gen)),
+    "schedule_query" => (Body::json::<ScheduleQueryInfo>(gen), Body::json::<Value>(gen)),
+    "batch" => (Empty, Body::json::<BatchInfo>(gen)),
+    "batch_cancel" | "tasks_cancel" => (Empty, Body::json::<Vec<u32>>(gen)),
+    "join" | "pipeline_delete" | "upload_session_delete" => (Empty, Empty),
+    "task_count" => (Empty, Body::json::<usize>(gen)),
+    "tasks" => (Body::json::<TasksParameters>(gen), Body::json::<Vec<TaskEntry>>(gen)),
+    "task" => (Empty, Body::json::<TaskResponse>(gen)),
+    "task_cancel" => (Empty, Body::json::<QueuedTask>(gen)),
+    "tasks_history" => (Empty, Body::json::<TaskHistory>(gen)),
+    "pipelines" => (Empty, Body::json::<Vec<PipelineRule>>(gen)),
+    "pipeline_create" | "pipeline_update" => (Body::json::<PipelineRule>(gen), Empty),
+    "attribute" => (Body::json::<AttributeInfo>(gen), Empty),
+    "events" => (Empty, Body::EventStream),
+    "query" => (Body::json::<QueryInfo>(gen), Body::one_of::<Vec<Value>, Page<Value>>(gen)),
+    "aggregate" => (Body::json::<AggregateInfo>(gen), Body::json::<Aggregation>(gen)),
+    "saved_queries" => (Empty, Body::json::<Vec<SavedQuery>>(gen)),
+    "saved_query_create" | "saved_query_update" => (Body::json::<SavedQuery>(gen), Empty),
+    "saved_query_delete" => (Empty, Empty),
+    "saved_query_run" => (Body::json::<RunQueryInfo>(gen), Body::one_of::<Vec<Value>, Page<Value>>(gen)),
+    "timeline" => (Body::json::<TimeRange>(gen), Body::json::<Vec<Value>>(gen)),
+    "save" => (Body::json::<SaveFile>(gen), Empty),
+    "load" => (Body::json::<SaveFile>(gen), Body::json::<LoadResult>(gen)),
+    "node_count" => (Empty, Body::json::<usize>(gen)),
+    "attribute_count" => (Empty, Body::json::<u64>(gen)),
+    "upload" => (Binary, Body::json::<UploadInfo>(gen)),
+    "uploads" => (Empty, Body::json::<Vec<UploadInfo>>(gen)),
+    "upload_session_create" => (Body::json::<NewUploadSession>(gen), Body::json::<UploadSession>(gen)),
+    "upload_sessions" => (Empty, Body::json::<Vec<UploadSession>>(gen)),
+    "upload_session" => (Empty, Body::json::<UploadSession>(gen)),
+    "upload_session_chunk" => (Binary, Body::json::<UploadSession>(gen)),
+    "upload_session_finalize" => (Empty, Body::json::<UploadInfo>(gen)),
+    "download" => (Body::json::<NodeId>(gen), Binary),
+    "download_id" => (Empty, Binary),
+    "read" => (Body::json::<ReadInfo>(gen), Binary),
+    "export" => (Body::json::<ExportInfo>(gen), Binary),
+    "audit" => (Empty, Body::json::<Vec<AuditEntry>>(gen)),
+    "api_docs" => (Empty, Html),
+    "api_docs_file" => (Empty, Binary),
+    "openapi_json" => (Empty, Body::json::<Value>(gen)),
+    _ => return None,
+  };
+  Some(bodies)
+}
+
+/// Return the schema of a path or query parameter of a route.
+fn route_parameter(route : &str, name : &str, gen : &mut SchemaGenerator) -> Option<Parameter>
+{
+  let parameter = match (route, name)
+  {
+    ("plugin", "plugin_name") | ("node_by_path", "path") | ("tasks_cancel", "plugin") | ("api_docs_file", "file") => Parameter::required::<String>(gen),
+    ("pipeline_update" | "pipeline_delete" | "saved_query_update" | "saved_query_delete" | "saved_query_run", "name") => Parameter::required::<String>(gen),
+    ("upload_session" | "upload_session_chunk" | "upload_session_finalize" | "upload_session_delete", "id") => Parameter::required::<String>(gen),
+    ("batch" | "batch_cancel", "batch_id") | ("task" | "task_cancel", "task_id") => Parameter::required::<u32>(gen),
+    ("tasks_history", "plugin" | "state") | ("audit", "user" | "route") => Parameter::optional::<String>(gen),
+    ("tasks_history" | "audit", "after" | "before") => Parameter::optional::<DateTime<Utc>>(gen),
+    ("events", "apikey" | "root") => Parameter::optional::<String>(gen),
+    ("events", "task_id") => Parameter::optional::<u32>(gen),
+    ("upload", "name") | ("download_id", "apikey") => Parameter::required::<String>(gen),
+    ("upload", "conflict") => Parameter::optional::<Conflict>(gen),
+    ("upload", "md5" | "sha1" | "sha256") => Parameter::optional::<String>(gen),
+    ("upload_session_chunk", "offset") => Parameter::required::<u64>(gen),
+    ("download_id", "node_id") => Parameter::object::<NodeId>(gen),
+    _ => return None,
+  };
+  Some(parameter)
+}
+
+/// Routes of the API, described for the OpenAPI document.
+struct ApiRoutes;
+
+impl Describe for ApiRoutes
+{
+  fn bodies(&self, route : &str, gen : &mut SchemaGenerator) -> Option<(Body, Body)>
+  {
+    route_bodies(route, gen)
+  }
+
+  fn parameter(&self, route : &str, name : &str, gen : &mut SchemaGenerator) -> Option<Parameter>
+  {
+    route_parameter(route, name, gen)
+  }
 }
 
 pub struct CORS;
@@ -674,7 +1519,7 @@ impl Fairing for CORS
   async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) 
   {
     response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-    response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, PATCH, OPTIONS"));
+    response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, PUT, PATCH, DELETE, OPTIONS"));
     response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
     response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
 
@@ -686,47 +1531,12 @@ impl Fairing for CORS
   }
 }
 
-struct ConfigApiKey(String);
-struct ApiKey<'r>(&'r str);
-
-#[derive(Debug)]
-enum ApiKeyError 
-{
-    Missing,
-    Invalid,
-}
-
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for ApiKey<'r> 
-{
-  type Error = ApiKeyError;
-
-  async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> 
-  {
-    /// Returns true if `key` is a valid API key string.
-    fn is_valid(api_key : &str, key : &str) -> bool 
-    {
-      api_key == key
-    }
-
-    let api_key = &req.guard::<&State<ConfigApiKey>>().await.succeeded().unwrap().inner().0;
-
-    match req.headers().get_one("x-api-key") 
-    {
-      Some(key) if is_valid(api_key, key) => Outcome::Success(ApiKey(key)),
-      None => Outcome::Failure((Status::Unauthorized, ApiKeyError::Missing)),
-      Some(_) => Outcome::Failure((Status::Unauthorized, ApiKeyError::Invalid)),
-    }
-  }
-}
-
-
 /// Launch the server.
 pub async fn serve(args : Arguments, session : Session) -> Result<(), Box<dyn Error>>
 {
   let session = Arc::new(session);
   let upload_dir = args.upload;
-  let config = Config { port: args.address.port(), 
+  let mut config = Config { port: args.address.port(), 
                         address: args.address.ip(), 
                         limits: Limits::default().limit("json", 10000.mebibytes()), 
                         ..Default::default() 
@@ -734,20 +1544,86 @@ pub async fn serve(args : Arguments, session : Session) -> Result<(), Box<dyn Er
   //config.limits = Limits::default().limit("bytes", 10000.mebibytes());
   //config.limits = Limits::default().limit("string", 10000.mebibytes());
 
-  //pass cert name in config
-  //config.tls = Some(TlsConfig::from_paths("cert.pem", "priv.pem"));
-  warn!("Using API key : {}", args.api_key);
-  let api_key = ConfigApiKey(args.api_key); 
+  let scheme = match (&args.tls_cert, &args.tls_key)
+  {
+    (Some(cert), Some(key)) =>
+    {
+      if args.tls_self_signed && !(Path::new(cert).exists() && Path::new(key).exists())
+      {
+        warn!("Generating self-signed certificate {} and key {}", cert, key);
+        tls::generate_self_signed(cert, key, &args.address)?;
+      }
+      config.tls = Some(TlsConfig::from_paths(cert, key));
+      "https"
+    },
+    (None, None) => 
+    {
+      warn!("TLS is not enabled");
+      "http"
+    },
+    _ => return Err("Both TLS certificate and key must be provided".into()),
+  };
+  info!("Listening on {}://{}", scheme, args.address);
+
+  for user in args.users.iter()
+  {
+    info!("Registered user {} with role {:?}", user.name, user.role);
+  }
+  let users = Users::new(args.users); 
+  let audit_log = AuditLog::open(&args.audit)?;
+  info!("Audit log : {}", args.audit);
+
+  fs::create_dir_all(&upload_dir)?;
+  let mut evidence_roots = args.evidence_roots;
+  evidence_roots.push(upload_dir.clone());
+  let upload_sessions = Arc::new(UploadSessions::open(Path::new(&upload_dir))?);
+  //incomplete uploads must not be loaded
+  let evidence_roots = Arc::new(EvidenceRoots::new(&evidence_roots).exclude(upload_sessions.dir())?);
+  for root in evidence_roots.roots()
+  {
+    info!("Evidence root : {}", root.display());
+  }
+
+  let events = Events::new();
+  events.watch_tree(session.clone());
+  //the task history is kept next to the audit log
+  let task_history = Path::new(&args.audit).with_extension("tasks.jsonl");
+  let task_queue = TaskQueue::start(session.clone(), events.clone(), args.max_tasks, args.concurrency, &task_history)?;
+  info!("Task history : {}", task_history.display());
+  info!("Running up to {} tasks at the same time", args.max_tasks);
+  let pipelines = Pipelines::start(session.clone(), task_queue.clone(), evidence_roots.clone(), args.pipelines)?;
+  //the saved queries are also kept next to the audit log, so they are not lost if the case isn't saved
+  let queries_file = Path::new(&args.audit).with_extension("queries.json");
+  let saved_queries = SavedQueries::open(&queries_file)?;
+  info!("Saved queries : {}", queries_file.display());
+  let query_cache = QueryCache::new(task_queue.clone());
+  query_cache.watch(&events);
 
   let rocket = rocket::custom(config)
           .attach(Shield::new()) 
           .attach(CORS)
           .manage(session)
           .manage(upload_dir)
-          .manage(api_key)
+          .manage(users)
+          .manage(audit_log)
+          .manage(evidence_roots)
+          .manage(upload_sessions)
+          .manage(task_queue)
+          .manage(events)
+          .manage(pipelines)
+          .manage(saved_queries)
+          .manage(query_cache)
+          .register("/", api_error::catchers())
           .mount("/api", routes![plugins, plugin, root, node, nodes, node_by_path, path, parent_id, run,
-                 task_count, join, task, tasks, attribute, save, load, node_count, attribute_count, 
-                 schedule, query, timeline, upload, download, read, download_id, delete]);
+                 task_count, join, task, tasks, task_cancel, tasks_cancel, tasks_history, schedule_query, batch, batch_cancel, pipelines, pipeline_create, pipeline_update, pipeline_delete, attribute, events, save, load, node_count, attribute_count, 
+                 schedule, query, aggregate, saved_queries, saved_query_create, saved_query_update, saved_query_delete, saved_query_run, timeline, upload, download, read, download_id, export, delete, audit,
+                 uploads, upload_session_create, upload_sessions, upload_session, upload_session_chunk, upload_session_finalize, upload_session_delete,
+                 openapi_json]);
+
+  #[cfg(feature = "api-docs")]
+  let rocket = rocket.mount("/api", routes![api_docs, api_docs_file]);
+  let openapi = api_document(rocket.routes(), "/api", &ApiRoutes)?;
+  let rocket = rocket.manage(openapi);
 
   #[cfg(feature = "frontend-dev")]
   let rocket = rocket.mount("/", FileServer::from("tapir-frontend/build"));
@@ -755,8 +1631,11 @@ pub async fn serve(args : Arguments, session : Session) -> Result<(), Box<dyn Er
   let rocket = rocket.mount("/", StaticFileServer::from());
 
   #[cfg(feature = "frontend")]
-  webbrowser::open(&("http://".to_owned() + &args.address.to_string())).unwrap();//+s for https 
-  let _ = rocket.launch().await.unwrap();
+  if let Err(err) = webbrowser::open(&(scheme.to_owned() + "://" + &args.address.to_string()))
+  {
+    warn!("Can't open the browser : {}", err);
+  }
+  rocket.launch().await?;
 
   Ok(())
 }
@@ -0,0 +1,954 @@
+//! Queue of the tasks scheduled through the API. Tasks wait here until they can be launched
+//! on the session task scheduler, so they can still be cancelled, and are followed until they finish.
+//! Tasks launched directly on the task scheduler, by `load` or by a plugin, are followed too,
+//! and every task is appended to the history file once it's done.
+
+use std::thread;
+use std::cmp::Reverse;
+use std::time::Duration;
+use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::cell::Cell;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+
+use tap::tree::TreeNodeId;
+use tap::task_scheduler::{TaskId, TaskState};
+use ::tap_query::filter::Filter;
+
+use crate::server::ArcSession;
+use crate::events::{Event, Events};
+use crate::jsonlines::JsonLines;
+
+/// Interval at which the state of the running tasks is checked.
+const POLL_INTERVAL : Duration = Duration::from_millis(100);
+/// Id of the first task of the queue, the ids of the queue never overlap the ids of the session task scheduler.
+pub const QUEUE_ID_BASE : u32 = 1 << 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskStatus
+{
+  Waiting,
+  Running,
+  Finished,
+  /// Cancelled before being launched.
+  Cancelled,
+}
+
+/// How a task was launched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskOrigin
+{
+  /// Scheduled through the queue by `schedule`, `schedule_query`, `run` or a pipeline.
+  Queue,
+  /// Launched directly on the session task scheduler, by `load` or by a plugin.
+  Scheduler,
+}
+
+/// Progress of a task, as seen from outside of the plugin.
+/// Plugins don't report what they read, so the bytes processed and the remaining time are estimated from the throughput of the plugin.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct TaskProgress
+{
+  /// Seconds since the task was launched.
+  pub elapsed : Option<f64>,
+  /// Size of the data of the node the task is applied to.
+  pub input_bytes : Option<u64>,
+  /// Bytes of the input read so far, estimated from the bytes per second of the previous tasks of the same plugin.
+  pub bytes_processed : Option<u64>,
+  /// Nodes added under the node the task is applied to since the task was launched.
+  pub nodes_created : usize,
+  /// Path of the node the task is applied to.
+  pub current_item : Option<String>,
+  /// Seconds before the end of the task, estimated from the bytes per second of the previous tasks of the same plugin.
+  pub estimated_remaining : Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct QueuedTask
+{
+  /// Id of the task in the queue, starting at `QUEUE_ID_BASE` and following the ids of the history.
+  pub id : u32,
+  /// Id of the task on the session task scheduler once it's launched.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub tap_id : Option<TaskId>,
+  pub origin : TaskOrigin,
+  pub plugin : String,
+  pub argument : Value,
+  pub relaunch : bool,
+  /// Tasks with a higher priority are launched first.
+  pub priority : i32,
+  /// Batch the task was scheduled with by `schedule_query`.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub batch : Option<u32>,
+  pub state : TaskStatus,
+  /// Position of a waiting task in the queue, 1 is the next task to launch.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub queue_position : Option<usize>,
+  /// Cancellation was asked while the task was running, plugins can't be interrupted so the task still run to the end.
+  pub cancel_requested : bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub result : Option<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error : Option<String>,
+  pub progress : TaskProgress,
+  pub queued : DateTime<Utc>,
+  pub started : Option<DateTime<Utc>>,
+  pub finished : Option<DateTime<Utc>>,
+  /// Seconds the task was running.
+  pub duration : Option<f64>,
+  /// Number of nodes under the input node when the task was launched.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub descendants_at_launch : Option<usize>,
+  /// Number of nodes under the input node when the task finished.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub descendants_at_finish : Option<usize>,
+  /// Node the task is applied to.
+  #[serde(skip)]
+  pub input : Option<TreeNodeId>,
+  #[serde(skip)]
+  arguments : String,
+  /// Attributes of the input node when the task was launched, to find the attributes set by the plugin.
+  #[serde(skip)]
+  input_attributes : Option<Map<String, Value>>,
+}
+
+fn seconds_since(start : DateTime<Utc>, now : DateTime<Utc>) -> f64
+{
+  (now - start).num_milliseconds() as f64 / 1000.0
+}
+
+impl QueuedTask
+{
+  pub fn is_done(&self) -> bool
+  {
+    matches!(self.state, TaskStatus::Finished | TaskStatus::Cancelled)
+  }
+
+  /// Cancel the task if it's waiting or flag it if it's running, return false if the task is already done or flagged.
+  fn cancel(&mut self) -> bool
+  {
+    match self.state
+    {
+      TaskStatus::Waiting =>
+      {
+        self.finish(TaskStatus::Cancelled);
+        true
+      },
+      TaskStatus::Running if !self.cancel_requested =>
+      {
+        self.cancel_requested = true;
+        true
+      },
+      _ => false,
+    }
+  }
+
+  fn finish(&mut self, state : TaskStatus)
+  {
+    let now = Utc::now();
+    self.state = state;
+    self.finished = Some(now);
+    self.duration = self.started.map(|started| seconds_since(started, now));
+    self.progress.elapsed = self.duration;
+    self.progress.estimated_remaining = None;
+    if state == TaskStatus::Finished && self.error.is_none()
+    {
+      self.progress.bytes_processed = self.progress.input_bytes;
+    }
+  }
+
+  /// Nodes added under the input node between the launch and the end of the task.
+  fn nodes_created(&self) -> Option<usize>
+  {
+    Some(self.descendants_at_finish?.saturating_sub(self.descendants_at_launch?))
+  }
+
+  /// Name of the state, tasks that finished with an error are `failed`.
+  fn state_name(&self) -> &'static str
+  {
+    match self.state
+    {
+      TaskStatus::Waiting => "waiting",
+      TaskStatus::Running => "running",
+      TaskStatus::Finished if self.error.is_some() => "failed",
+      TaskStatus::Finished => "finished",
+      TaskStatus::Cancelled => "cancelled",
+    }
+  }
+}
+
+/// Filter used to select tasks from the history.
+#[derive(Debug, Default)]
+pub struct TaskFilter
+{
+  pub plugin : Option<String>,
+  /// `waiting`, `running`, `finished`, `failed` or `cancelled`, finished include failed tasks.
+  pub state : Option<String>,
+  /// Tasks queued after this date.
+  pub after : Option<DateTime<Utc>>,
+  /// Tasks queued before this date.
+  pub before : Option<DateTime<Utc>>,
+}
+
+impl TaskFilter
+{
+  fn matches(&self, task : &QueuedTask) -> bool
+  {
+    self.plugin.as_ref().map_or(true, |plugin| *plugin == task.plugin) &&
+    self.state.as_ref().map_or(true, |state| state == task.state_name() || (state == "finished" && task.state == TaskStatus::Finished)) &&
+    self.after.map_or(true, |after| task.queued >= after) &&
+    self.before.map_or(true, |before| task.queued <= before)
+  }
+}
+
+/// Timings of the tasks of a plugin.
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct PluginSummary
+{
+  pub count : usize,
+  pub failed : usize,
+  pub cancelled : usize,
+  /// Tasks that were launched and are done, used for the durations.
+  pub runs : usize,
+  /// Seconds spent running the tasks.
+  pub total_duration : f64,
+  pub average_duration : Option<f64>,
+  pub max_duration : Option<f64>,
+  /// Size of the data of the nodes the tasks were applied to, plugins don't report what they actually read.
+  pub input_bytes : u64,
+  /// Nodes added under the nodes the tasks were applied to.
+  pub nodes_created : usize,
+}
+
+/// Tasks selected from the history and summary of their timings by plugin.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct TaskHistory
+{
+  pub tasks : Vec<QueuedTask>,
+  pub plugins : HashMap<String, PluginSummary>,
+}
+
+/// Tasks of a batch, counted by state.
+#[derive(Debug, Default, Serialize, JsonSchema)]
+pub struct BatchInfo
+{
+  pub id : u32,
+  pub tasks : Vec<u32>,
+  pub waiting : usize,
+  pub running : usize,
+  pub finished : usize,
+  /// Tasks that finished with an error.
+  pub failed : usize,
+  pub cancelled : usize,
+}
+
+/// State of a task on the session task scheduler, or in the queue while it's not launched.
+pub struct TaskInfo
+{
+  /// `waiting`, `running`, `finished` or `cancelled`.
+  pub state : &'static str,
+  /// Id of the task on the session task scheduler, or in the queue if it's not launched.
+  pub id : u32,
+  pub plugin : String,
+  pub argument : Value,
+  /// Task as returned by the session task scheduler.
+  pub task : Value,
+  /// Result or error of a finished task.
+  pub result : Option<Result<Value, String>>,
+  /// The task as followed by the queue.
+  pub queued : Option<QueuedTask>,
+}
+
+/// Return the first node id found in the arguments of a task, that's the node the plugin is applied to.
+fn input_node(argument : &Value) -> Option<TreeNodeId>
+{
+  match argument
+  {
+    Value::Object(map) => serde_json::from_value(argument.clone()).ok().or_else(|| map.values().find_map(input_node)),
+    Value::Array(values) => values.iter().find_map(input_node),
+    _ => None,
+  }
+}
+
+pub struct TaskQueue
+{
+  session : ArcSession,
+  events : Events,
+  /// Maximum number of tasks launched at the same time.
+  max_running : usize,
+  /// Maximum number of tasks launched at the same time for some plugins.
+  plugin_limits : HashMap<String, usize>,
+  tasks : Mutex<Vec<QueuedTask>>,
+  /// Id of the first task in `tasks`, following the tasks of the history so an id is never reused after a restart.
+  first_id : u32,
+  /// Index in `tasks` of the tasks launched on the session task scheduler, by task scheduler id.
+  tap_ids : Mutex<HashMap<TaskId, usize>>,
+  changed : Condvar,
+  /// Bytes and seconds of the tasks that finished, by plugin.
+  throughput : Mutex<HashMap<String, (u64, f64)>>,
+  /// Receive the tasks when they are finished or cancelled.
+  finished_listeners : Mutex<Vec<Sender<QueuedTask>>>,
+  /// Id of the tasks of each batch.
+  batches : Mutex<Vec<Vec<u32>>>,
+  /// First id of the session task scheduler that was not checked yet.
+  next_tap_id : Mutex<TaskId>,
+  /// Tasks that are done.
+  history : JsonLines,
+}
+
+impl TaskQueue
+{
+  /// Create the queue and start the thread that launch and follow the tasks, 
+  /// the tasks are appended to `history_path` once they are done.
+  pub fn start<P : AsRef<Path>>(session : ArcSession, events : Events, max_running : usize, plugin_limits : HashMap<String, usize>, history_path : P) -> io::Result<Arc<TaskQueue>>
+  {
+    let history = JsonLines::open(history_path)?;
+    let last_id = Cell::new(None);
+    history.read::<QueuedTask, _>(|task| { last_id.set(last_id.get().max(Some(task.id))); false })?;
+    let first_id = last_id.get().map_or(QUEUE_ID_BASE, |id : u32| id.saturating_add(1).max(QUEUE_ID_BASE));
+
+    let queue = Arc::new(TaskQueue{ session, events, max_running : max_running.max(1), plugin_limits, 
+                                    tasks : Mutex::new(Vec::new()), 
+                                    first_id,
+                                    tap_ids : Mutex::new(HashMap::new()),
+                                    changed : Condvar::new(),
+                                    throughput : Mutex::new(HashMap::new()),
+                                    finished_listeners : Mutex::new(Vec::new()),
+                                    batches : Mutex::new(Vec::new()),
+                                    next_tap_id : Mutex::new(0),
+                                    history });
+    let dispatcher = queue.clone();
+    thread::spawn(move || dispatcher.dispatch());
+    Ok(queue)
+  }
+
+  fn lock(&self) -> MutexGuard<'_, Vec<QueuedTask>>
+  {
+    self.tasks.lock().unwrap()
+  }
+
+  /// Return the index in `tasks` of a task from it's id in the queue or on the session task scheduler.
+  fn index(&self, id : u32) -> Option<usize>
+  {
+    match id.checked_sub(self.first_id)
+    {
+      Some(index) => Some(index as usize),
+      None => self.tap_ids.lock().unwrap().get(&id).copied(),
+    }
+  }
+
+  /// Wake the threads waiting on the queue and send the new state of `task` to the clients.
+  fn notify(&self, task : &QueuedTask)
+  {
+    self.changed.notify_all();
+    let path = task.input.and_then(|node_id| self.session.tree.node_path(node_id));
+    self.events.send(Event::Task{ task : task.clone(), path });
+
+    if task.is_done()
+    {
+      self.record(task);
+      self.finished_listeners.lock().unwrap().retain(|listener| listener.send(task.clone()).is_ok());
+    }
+  }
+
+  /// Append a task that is done to the history file.
+  fn record(&self, task : &QueuedTask)
+  {
+    if let Err(err) = self.history.append(task)
+    {
+      warn!("Can't write task {} to {} : {}", task.id, self.history.path().display(), err);
+    }
+  }
+
+  /// Return a channel that receive every task once it's finished or cancelled.
+  pub fn subscribe_finished(&self) -> Receiver<QueuedTask>
+  {
+    let (sender, receiver) = mpsc::channel();
+    self.finished_listeners.lock().unwrap().push(sender);
+    receiver
+  }
+
+  /// Add a task to the queue and return it's id.
+  pub fn push(&self, plugin : &str, arguments : &str, relaunch : bool, priority : i32) -> io::Result<u32>
+  {
+    let ids = self.push_tasks(plugin, &[arguments.to_string()], relaunch, priority, None)?;
+    Ok(ids[0])
+  }
+
+  /// Add a task for each of `arguments` to the queue as a batch, return the batch id and the tasks id.
+  pub fn push_batch(&self, plugin : &str, arguments : &[String], relaunch : bool, priority : i32) -> io::Result<(u32, Vec<u32>)>
+  {
+    let mut batches = self.batches.lock().unwrap();
+    let batch = batches.len() as u32;
+    let ids = self.push_tasks(plugin, arguments, relaunch, priority, Some(batch))?;
+    batches.push(ids.clone());
+    Ok((batch, ids))
+  }
+
+  /// Add the tasks to the queue, no task is added if one of the arguments is invalid.
+  fn push_tasks(&self, plugin : &str, arguments : &[String], relaunch : bool, priority : i32, batch : Option<u32>) -> io::Result<Vec<u32>>
+  {
+    if self.session.plugins_db.find(plugin).is_none()
+    {
+      return Err(io::Error::new(io::ErrorKind::NotFound, format!("Plugin {} not found", plugin)))
+    }
+    let mut parsed = Vec::with_capacity(arguments.len());
+    for arguments in arguments
+    {
+      let argument : Value = serde_json::from_str(arguments)
+                            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid arguments : {}", err)))?;
+      parsed.push((input_node(&argument), argument, arguments.clone()));
+    }
+
+    let mut tasks = self.lock();
+    let mut ids = Vec::with_capacity(parsed.len());
+    for (input, argument, arguments) in parsed
+    {
+      let id = self.first_id + tasks.len() as u32;
+      let task = QueuedTask{ id, tap_id : None, origin : TaskOrigin::Queue, plugin : plugin.into(), argument, relaunch, priority, batch, 
+                             state : TaskStatus::Waiting, queue_position : None, cancel_requested : false,
+                             result : None, error : None, progress : TaskProgress::default(), 
+                             queued : Utc::now(), started : None, finished : None, duration : None,
+                             descendants_at_launch : None, descendants_at_finish : None, input, arguments, input_attributes : None };
+      self.notify(&task);
+      tasks.push(task);
+      ids.push(id);
+    }
+    Ok(ids)
+  }
+
+  /// Return the state of the tasks of a batch.
+  pub fn batch(&self, batch : u32) -> Option<BatchInfo>
+  {
+    let ids = self.batches.lock().unwrap().get(batch as usize)?.clone();
+    let mut info = BatchInfo{ id : batch, tasks : ids.clone(), ..Default::default() };
+
+    for task in self.list(&ids)
+    {
+      match task.state
+      {
+        TaskStatus::Waiting => info.waiting += 1,
+        TaskStatus::Running => info.running += 1,
+        TaskStatus::Finished if task.error.is_some() => info.failed += 1,
+        TaskStatus::Finished => info.finished += 1,
+        TaskStatus::Cancelled => info.cancelled += 1,
+      }
+    }
+    Some(info)
+  }
+
+  /// Cancel all the tasks of a batch that are not done, return the id of the cancelled tasks.
+  pub fn cancel_batch(&self, batch : u32) -> Vec<u32>
+  {
+    self.cancel_matching(|task| task.batch == Some(batch))
+  }
+
+  /// Id of the waiting tasks in the order they will be launched.
+  fn waiting_order(tasks : &[QueuedTask]) -> Vec<u32>
+  {
+    let mut waiting : Vec<&QueuedTask> = tasks.iter().filter(|task| task.state == TaskStatus::Waiting && task.origin == TaskOrigin::Queue).collect();
+    waiting.sort_by_key(|task| (Reverse(task.priority), task.id));
+    waiting.iter().map(|task| task.id).collect()
+  }
+
+  /// Return a task from it's id in the queue or on the session task scheduler.
+  pub fn get(&self, id : u32) -> Option<QueuedTask>
+  {
+    self.list(&[id]).pop()
+  }
+
+  /// Return the tasks matching `ids` with their position in the queue, unknown ids
+  /// and tasks of the session task scheduler that were not scheduled through the queue are ignored.
+  pub fn list(&self, ids : &[u32]) -> Vec<QueuedTask>
+  {
+    let indexes : Vec<usize> = ids.iter().filter_map(|id| self.index(*id)).collect();
+    let tasks = self.lock();
+    let positions : HashMap<u32, usize> = Self::waiting_order(&tasks).into_iter().enumerate().map(|(index, id)| (id, index + 1)).collect();
+
+    indexes.into_iter().filter_map(|index| tasks.get(index).cloned())
+              .map(|mut task| 
+              {
+                task.queue_position = positions.get(&task.id).copied();
+                task
+              })
+              .collect()
+  }
+
+  /// Return the state of a task from the session task scheduler, tasks scheduled through the queue
+  /// are found with their id in the queue or on the task scheduler and have their queue info.
+  pub fn task_info(&self, id : u32) -> Option<TaskInfo>
+  {
+    let queued = self.get(id);
+    let tap_id = match &queued
+    {
+      Some(task) => task.tap_id,
+      None if id < QUEUE_ID_BASE => Some(id),
+      None => return None,
+    };
+
+    match tap_id.and_then(|tap_id| self.session.task_scheduler.task(tap_id))
+    {
+      Some(task_state) =>
+      {
+        let (state, task, result) = match task_state
+        {
+          TaskState::Waiting(task) => ("waiting", task, None),
+          TaskState::Launched(task) => ("running", task, None),
+          TaskState::Finished(task, result) => ("finished", task, Some(result.map(|result| serde_json::to_value(result).unwrap_or(Value::Null))
+                                                                               .map_err(|err| err.to_string()))),
+        };
+        Some(TaskInfo{ state, id : task.id, plugin : task.plugin_name.clone(), argument : serde_json::from_str(&task.argument).unwrap_or(Value::Null),
+                       task : json!(task), result, queued })
+      },
+      //not launched yet, cancelled before it's launch or refused by the task scheduler
+      None =>
+      {
+        let queued = queued?;
+        let result = match (&queued.error, queued.state)
+        {
+          (Some(err), _) => Some(Err(err.clone())),
+          (None, TaskStatus::Finished) => Some(Ok(queued.result.clone().unwrap_or(Value::Null))),
+          _ => None,
+        };
+        let state = match queued.state
+        {
+          TaskStatus::Waiting => "waiting",
+          TaskStatus::Running => "running",
+          TaskStatus::Finished => "finished",
+          TaskStatus::Cancelled => "cancelled",
+        };
+        Some(TaskInfo{ state, id : queued.id, plugin : queued.plugin.clone(), argument : queued.argument.clone(),
+                       task : json!({"id" : queued.id, "plugin_name" : queued.plugin, "argument" : queued.arguments}), result, queued : Some(queued) })
+      },
+    }
+  }
+
+  /// Return the number of tasks created on the session task scheduler, by the queue, the routes or the plugins.
+  pub fn scheduler_task_count(&self) -> usize
+  {
+    self.session.task_scheduler.task_count()
+  }
+
+  /// Return true if a task is waiting or running on the session task scheduler,
+  /// the subtasks launched by the plugins are checked even if they weren't followed yet by the queue.
+  pub fn scheduler_active(&self) -> bool
+  {
+    let followed : Vec<TaskId> = self.lock().iter().filter(|task| !task.is_done()).filter_map(|task| task.tap_id).collect();
+    let next_tap_id = *self.next_tap_id.lock().unwrap();
+    let count = self.session.task_scheduler.task_count() as TaskId;
+
+    followed.into_iter().chain(next_tap_id..count)
+            .any(|tap_id| matches!(self.session.task_scheduler.task(tap_id), Some(TaskState::Waiting(_)) | Some(TaskState::Launched(_))))
+  }
+
+  /// Cancel a task. Waiting tasks are never launched, plugins can't be interrupted
+  /// so running tasks are only flagged with `cancel_requested` and finish normally.
+  pub fn cancel(&self, id : u32) -> io::Result<QueuedTask>
+  {
+    let index = self.index(id);
+    let mut tasks = self.lock();
+    let task = index.and_then(|index| tasks.get_mut(index)).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Task {} not found in the queue", id)))?;
+    if task.origin != TaskOrigin::Queue
+    {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Task {} was not scheduled through the queue and can't be cancelled", id)))
+    }
+    if task.cancel()
+    {
+      self.notify(task);
+    }
+    Ok(task.clone())
+  }
+
+  /// Cancel all the tasks of a plugin that are not done, return the id of the cancelled tasks.
+  pub fn cancel_plugin(&self, plugin : &str) -> Vec<u32>
+  {
+    self.cancel_matching(|task| task.plugin == plugin)
+  }
+
+  fn cancel_matching<F : Fn(&QueuedTask) -> bool>(&self, filter : F) -> Vec<u32>
+  {
+    let mut tasks = self.lock();
+    let mut cancelled = Vec::new();
+    for task in tasks.iter_mut().filter(|task| task.origin == TaskOrigin::Queue && filter(task))
+    {
+      if task.cancel()
+      {
+        self.notify(task);
+        cancelled.push(task.id);
+      }
+    }
+    cancelled
+  }
+
+  /// Return the tasks that are done from the history file, including the tasks of the previous runs of the server,
+  /// and the current tasks, matching `filter` with the summary of their timings by plugin.
+  pub fn history(&self, filter : &TaskFilter) -> io::Result<TaskHistory>
+  {
+    let current : Vec<QueuedTask> = self.lock().iter().filter(|task| !task.is_done() && filter.matches(task)).cloned().collect();
+
+    let mut tasks : Vec<QueuedTask> = self.history.read(|task| filter.matches(task))?;
+    //tasks that finished since they were copied are already in the history file
+    let done : HashSet<(u32, DateTime<Utc>)> = tasks.iter().map(|task| (task.id, task.queued)).collect();
+    tasks.extend(current.into_iter().filter(|task| !done.contains(&(task.id, task.queued))));
+
+    let mut plugins : HashMap<String, PluginSummary> = HashMap::new();
+    for task in tasks.iter()
+    {
+      let summary = plugins.entry(task.plugin.clone()).or_default();
+      summary.count += 1;
+      match task.state_name()
+      {
+        "failed" => summary.failed += 1,
+        "cancelled" => summary.cancelled += 1,
+        _ => (),
+      }
+      if let (Some(duration), true) = (task.duration, task.is_done())
+      {
+        summary.runs += 1;
+        summary.total_duration += duration;
+        summary.max_duration = Some(summary.max_duration.map_or(duration, |max| max.max(duration)));
+        summary.input_bytes += task.progress.input_bytes.unwrap_or(0);
+        summary.nodes_created += task.nodes_created().unwrap_or(0);
+      }
+    }
+    for summary in plugins.values_mut()
+    {
+      summary.average_duration = (summary.runs > 0).then(|| summary.total_duration / summary.runs as f64);
+    }
+
+    Ok(TaskHistory{ tasks, plugins })
+  }
+
+  /// Block until a task is done and return it.
+  pub fn wait(&self, id : u32) -> io::Result<QueuedTask>
+  {
+    let index = self.index(id);
+    let mut tasks = self.lock();
+    loop
+    {
+      match index.and_then(|index| tasks.get(index))
+      {
+        Some(task) if task.is_done() => return Ok(task.clone()),
+        Some(_) => tasks = self.changed.wait(tasks).unwrap(),
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("Task {} not found in the queue", id))),
+      }
+    }
+  }
+
+  /// Block until all the tasks of the queue are done.
+  pub fn join(&self)
+  {
+    let mut tasks = self.lock();
+    while tasks.iter().any(|task| !task.is_done())
+    {
+      tasks = self.changed.wait(tasks).unwrap();
+    }
+  }
+
+  /// Launch the waiting tasks when there is room and follow the running ones until the end.
+  fn dispatch(&self)
+  {
+    let mut last_count = self.session.tree.count();
+    loop
+    {
+      for (id, plugin, arguments, relaunch) in self.next_tasks()
+      {
+        let result = self.session.schedule(&plugin, arguments, relaunch);
+
+        let mut tasks = self.lock();
+        let index = (id - self.first_id) as usize;
+        let task = &mut tasks[index];
+        match result
+        {
+          Ok(tap_id) =>
+          {
+            task.tap_id = Some(tap_id);
+            self.tap_ids.lock().unwrap().insert(tap_id, index);
+            self.notify(task);
+          },
+          Err(err) =>
+          {
+            task.error = Some(err.to_string());
+            task.finish(TaskStatus::Finished);
+            self.notify(task);
+          },
+        }
+      }
+
+      //the nodes under the input of the tasks are only counted again when nodes were added or removed
+      let count = self.session.tree.count();
+      self.update_progress(count != last_count);
+      last_count = count;
+      self.poll_scheduler();
+
+      let tasks = self.lock();
+      let _ = self.changed.wait_timeout(tasks, POLL_INTERVAL).unwrap();
+    }
+  }
+
+  /// Mark the next tasks to launch as running and return them, 
+  /// by priority and without exceeding the global and plugins limits.
+  fn next_tasks(&self) -> Vec<(u32, String, String, bool)>
+  {
+    let mut tasks = self.lock();
+    let mut launch = Vec::new();
+    let mut running = 0;
+    let mut plugin_running : HashMap<String, usize> = HashMap::new();
+    for task in tasks.iter().filter(|task| task.state == TaskStatus::Running && task.origin == TaskOrigin::Queue)
+    {
+      running += 1;
+      *plugin_running.entry(task.plugin.clone()).or_insert(0) += 1;
+    }
+    if running >= self.max_running
+    {
+      return launch
+    }
+
+    for id in Self::waiting_order(&tasks)
+    {
+      if running >= self.max_running
+      {
+        break
+      }
+
+      let task = &mut tasks[(id - self.first_id) as usize];
+      let plugin_count = plugin_running.entry(task.plugin.clone()).or_insert(0);
+      if self.plugin_limits.get(&task.plugin).map_or(false, |limit| *plugin_count >= *limit)
+      {
+        continue
+      }
+      *plugin_count += 1;
+
+      self.launch(task);
+      self.notify(task);
+      running += 1;
+      launch.push((task.id, task.plugin.clone(), task.arguments.clone(), task.relaunch));
+    }
+    launch
+  }
+
+  /// Mark a task as running and keep the state of it's input node, to follow what the plugin change.
+  fn launch(&self, task : &mut QueuedTask)
+  {
+    task.state = TaskStatus::Running;
+    task.started = Some(Utc::now());
+    if let Some(node_id) = task.input
+    {
+      task.progress.input_bytes = self.data_size(node_id);
+      task.progress.current_item = self.session.tree.node_path(node_id);
+      task.input_attributes = self.attributes(node_id);
+      task.descendants_at_launch = self.descendant_count(node_id);
+    }
+  }
+
+  /// Return the number of nodes under `node_id`.
+  fn descendant_count(&self, node_id : TreeNodeId) -> Option<usize>
+  {
+    let path = self.session.tree.node_path(node_id)?;
+    //the query return the node itself with it's descendants
+    Filter::path(&self.session.tree, "name matches '*'", &path).ok().map(|nodes_id| nodes_id.len().saturating_sub(1))
+  }
+
+  /// Return the nodes added under the input node of `task` since it was launched.
+  fn nodes_created_since_launch(&self, task : &QueuedTask) -> Option<usize>
+  {
+    let count = self.descendant_count(task.input?)?;
+    Some(count.saturating_sub(task.descendants_at_launch?))
+  }
+
+  fn data_size(&self, node_id : TreeNodeId) -> Option<u64>
+  {
+    let node = self.session.tree.get_node_from_id(node_id)?;
+    let data = node.value().get_value("data")?;
+    Some(data.as_vfile_builder().size())
+  }
+
+  fn attributes(&self, node_id : TreeNodeId) -> Option<Map<String, Value>>
+  {
+    let node = self.session.tree.get_node_from_id(node_id)?;
+    match json!(node)
+    {
+      Value::Object(attributes) => Some(attributes),
+      _ => None,
+    }
+  }
+
+  /// Send an event for each attribute of the input node that the task added or changed,
+  /// plugins modify the nodes directly so that's the only way to know what they set.
+  fn send_attributes(&self, task : &mut QueuedTask)
+  {
+    let (node_id, before) = match (task.input, task.input_attributes.take())
+    {
+      (Some(node_id), Some(before)) => (node_id, before),
+      _ => return,
+    };
+    let (after, path) = match (self.attributes(node_id), self.session.tree.node_path(node_id))
+    {
+      (Some(after), Some(path)) => (after, path),
+      _ => return,
+    };
+
+    for (name, value) in after
+    {
+      if before.get(&name) != Some(&value)
+      {
+        self.events.send(Event::Attribute{ node_id, path : path.clone(), name });
+      }
+    }
+  }
+
+  /// Update the progress of the running tasks, the nodes created are counted again only if `tree_changed`.
+  fn update_progress(&self, tree_changed : bool)
+  {
+    //the nodes are counted without the lock, as it can take some time on large trees
+    let running : Vec<QueuedTask> = match tree_changed
+    {
+      true => self.lock().iter().filter(|task| task.state == TaskStatus::Running).cloned().collect(),
+      false => Vec::new(),
+    };
+    let created : HashMap<u32, usize> = running.iter().filter_map(|task| Some((task.id, self.nodes_created_since_launch(task)?))).collect();
+
+    let now = Utc::now();
+    let mut tasks = self.lock();
+    let throughput = self.throughput.lock().unwrap();
+    for task in tasks.iter_mut().filter(|task| task.state == TaskStatus::Running)
+    {
+      if let Some(nodes_created) = created.get(&task.id)
+      {
+        task.progress.nodes_created = *nodes_created;
+      }
+
+      let elapsed = task.started.map(|started| seconds_since(started, now));
+      task.progress.elapsed = elapsed;
+      //bytes per second of the previous tasks of the plugin
+      let rate = throughput.get(&task.plugin).filter(|(bytes, seconds)| *bytes > 0 && *seconds > 0.0).map(|(bytes, seconds)| *bytes as f64 / seconds);
+      match (elapsed, task.progress.input_bytes, rate)
+      {
+        (Some(elapsed), Some(total), Some(rate)) =>
+        {
+          let processed = (rate * elapsed).min(total as f64);
+          task.progress.bytes_processed = Some(processed as u64);
+          task.progress.estimated_remaining = Some((total as f64 - processed) / rate);
+        },
+        _ =>
+        {
+          task.progress.bytes_processed = None;
+          task.progress.estimated_remaining = None;
+        },
+      }
+    }
+  }
+
+  /// Keep the bytes per second of a task that finished without error, used to estimate the progress of the next tasks of the plugin.
+  fn record_throughput(&self, task : &QueuedTask)
+  {
+    if let (TaskStatus::Finished, None, Some(bytes), Some(duration)) = (task.state, &task.error, task.progress.input_bytes, task.duration)
+    {
+      let mut throughput = self.throughput.lock().unwrap();
+      let (total_bytes, seconds) = throughput.entry(task.plugin.clone()).or_insert((0, 0.0));
+      *total_bytes += bytes;
+      *seconds += duration;
+    }
+  }
+
+  /// Update a task from it's state on the session task scheduler, return true if it changed.
+  fn apply_state(&self, task : &mut QueuedTask, state : TaskState) -> bool
+  {
+    match state
+    {
+      TaskState::Launched(_) if task.state == TaskStatus::Waiting =>
+      {
+        self.launch(task);
+        true
+      },
+      TaskState::Finished(_, result) =>
+      {
+        match result
+        {
+          Ok(result) => task.result = Some(serde_json::to_value(result).unwrap_or(Value::Null)),
+          Err(err) => task.error = Some(err.to_string()),
+        }
+        task.descendants_at_finish = task.input.and_then(|node_id| self.descendant_count(node_id));
+        task.finish(TaskStatus::Finished);
+        if let Some(nodes_created) = task.nodes_created()
+        {
+          task.progress.nodes_created = nodes_created;
+        }
+        self.record_throughput(task);
+        self.send_attributes(task);
+        true
+      },
+      _ => false,
+    }
+  }
+
+  /// Create the task followed for a task launched directly on the session task scheduler.
+  fn scheduler_task(&self, id : u32, tap_id : TaskId, state : TaskState) -> QueuedTask
+  {
+    let scheduled = match &state
+    {
+      TaskState::Waiting(task) | TaskState::Launched(task) | TaskState::Finished(task, _) => task,
+    };
+    let argument : Value = serde_json::from_str(&scheduled.argument).unwrap_or(Value::Null);
+    let mut task = QueuedTask{ id, tap_id : Some(tap_id), origin : TaskOrigin::Scheduler, plugin : scheduled.plugin_name.clone(), 
+                               input : input_node(&argument), argument, arguments : scheduled.argument.clone(), input_attributes : None, descendants_at_launch : None, descendants_at_finish : None, relaunch : false, priority : 0, batch : None,
+                               state : TaskStatus::Waiting, queue_position : None, cancel_requested : false,
+                               result : None, error : None, progress : TaskProgress::default(),
+                               queued : Utc::now(), started : None, finished : None, duration : None };
+    self.apply_state(&mut task, state);
+    task
+  }
+
+  /// Update the tasks that were launched or finished on the session task scheduler,
+  /// and follow the tasks that were launched directly on the task scheduler since the last poll.
+  fn poll_scheduler(&self)
+  {
+    let active : Vec<(usize, TaskId)> = self.lock().iter().enumerate()
+                                            .filter(|(_, task)| !task.is_done())
+                                            .filter_map(|(index, task)| task.tap_id.map(|tap_id| (index, tap_id)))
+                                            .collect();
+    let states : Vec<(usize, TaskState)> = active.into_iter()
+                                                 .filter_map(|(index, tap_id)| Some((index, self.session.task_scheduler.task(tap_id)?)))
+                                                 .collect();
+
+    //counted after reading the states, so a task created by a plugin is found before it's parent is seen finished
+    let count = self.session.task_scheduler.task_count() as TaskId;
+    let mut next_tap_id = self.next_tap_id.lock().unwrap();
+    let new_tasks : Vec<(TaskId, TaskState)> = 
+    {
+      let tap_ids = self.tap_ids.lock().unwrap();
+      (*next_tap_id..count).filter(|tap_id| !tap_ids.contains_key(tap_id))
+                           .filter_map(|tap_id| Some((tap_id, self.session.task_scheduler.task(tap_id)?)))
+                           .collect()
+    };
+    *next_tap_id = count;
+
+    let mut tasks = self.lock();
+    for (index, state) in states
+    {
+      let task = &mut tasks[index];
+      if self.apply_state(task, state)
+      {
+        self.notify(task);
+      }
+    }
+    for (tap_id, state) in new_tasks
+    {
+      let index = tasks.len();
+      let task = self.scheduler_task(self.first_id + index as u32, tap_id, state);
+      self.tap_ids.lock().unwrap().insert(tap_id, index);
+      self.notify(&task);
+      tasks.push(task);
+    }
+  }
+}
@@ -0,0 +1,52 @@
+//! Generation of the self-signed certificate used when no certificate is provided.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::error::Error;
+use std::net::SocketAddr;
+
+/// Generate a self-signed certificate for `localhost` and the listening address,
+/// and write the certificate and the private key in pem format.
+pub fn generate_self_signed(cert_path : &str, key_path : &str, address : &SocketAddr) -> Result<(), Box<dyn Error>>
+{
+  let names = vec![String::from("localhost"), address.ip().to_string()];
+  let cert = rcgen::generate_simple_self_signed(names)?;
+
+  //both files are created before being written, so an existing file is never overwritten
+  let create_new = |path : &str, other : &str, mode : u32| -> Result<File, Box<dyn Error>>
+  {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::OpenOptionsExt;
+      options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    options.open(path).map_err(|err| match err.kind()
+    {
+      io::ErrorKind::AlreadyExists => format!("Can't generate the self-signed certificate, {} already exists but {} doesn't, remove it or provide both files", path, other).into(),
+      _ => format!("Can't create {} : {}", path, err).into(),
+    })
+  };
+
+  //the key is only readable by the owner from it's creation
+  let mut key_file = create_new(key_path, cert_path, 0o600)?;
+  let mut cert_file = match create_new(cert_path, key_path, 0o644)
+  {
+    Ok(cert_file) => cert_file,
+    Err(err) =>
+    {
+      drop(key_file);
+      let _ = fs::remove_file(key_path);
+      return Err(err)
+    },
+  };
+
+  key_file.write_all(cert.serialize_private_key_pem().as_bytes())?;
+  cert_file.write_all(cert.serialize_pem()?.as_bytes())?;
+
+  Ok(())
+}
@@ -0,0 +1,464 @@
+//! Storage of the files uploaded to the server.
+
+use std::pin::Pin;
+use std::io::{self, Read};
+use std::fs::{self, OpenOptions};
+use std::task::{Context, Poll};
+use std::path::{Component, Path, PathBuf};
+
+use log::warn;
+use uuid::Uuid;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use rocket::data::{Data, ToByteUnit};
+use rocket::tokio::io::AsyncWrite;
+use rocket::tokio::fs::{self as async_fs, File};
+
+/// Directory inside the upload directory where the resumable upload sessions are kept.
+pub const SESSIONS_DIR : &str = ".sessions";
+/// Suffix added to an uploaded file name to get the name of it's manifest.
+pub const MANIFEST_SUFFIX : &str = ".manifest.json";
+
+/// What to do when an uploaded file already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, FromFormField, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Conflict
+{
+  /// Refuse the upload.
+  #[default]
+  Reject,
+  /// Store the file under a new name.
+  Rename,
+  /// Replace the existing file.
+  Overwrite,
+}
+
+/// Hex encoded hashes of an uploaded file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Hashes
+{
+  pub md5 : String,
+  pub sha1 : String,
+  pub sha256 : String,
+}
+
+/// Hashes provided by the client, checked against the received data.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ExpectedHashes
+{
+  pub md5 : Option<String>,
+  pub sha1 : Option<String>,
+  pub sha256 : Option<String>,
+}
+
+impl ExpectedHashes
+{
+  pub fn is_empty(&self) -> bool
+  {
+    self.md5.is_none() && self.sha1.is_none() && self.sha256.is_none()
+  }
+
+  /// Check that each provided hash match `hashes`.
+  pub fn verify(&self, hashes : &Hashes) -> io::Result<()>
+  {
+    let pairs = [("md5", &self.md5, &hashes.md5), ("sha1", &self.sha1, &hashes.sha1), ("sha256", &self.sha256, &hashes.sha256)];
+
+    for (name, expected, hash) in pairs
+    {
+      match expected
+      {
+        Some(expected) if !expected.eq_ignore_ascii_case(hash) =>
+          return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} mismatch, expected {} got {}", name, expected, hash))),
+        _ => (),
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Compute md5, sha1 and sha256 in one pass.
+#[derive(Default)]
+pub struct Hasher
+{
+  md5 : Md5,
+  sha1 : Sha1,
+  sha256 : Sha256,
+}
+
+impl Hasher
+{
+  pub fn update(&mut self, data : &[u8])
+  {
+    self.md5.update(data);
+    self.sha1.update(data);
+    self.sha256.update(data);
+  }
+
+  pub fn finalize(self) -> Hashes
+  {
+    Hashes{ md5 : format!("{:x}", self.md5.finalize()),
+            sha1 : format!("{:x}", self.sha1.finalize()),
+            sha256 : format!("{:x}", self.sha256.finalize()) }
+  }
+}
+
+/// Writer that hash the data while it's written.
+pub struct HashingWriter<W>
+{
+  inner : W,
+  hasher : Hasher,
+}
+
+impl<W> HashingWriter<W>
+{
+  pub fn new(inner : W) -> Self
+  {
+    HashingWriter{ inner, hasher : Hasher::default() }
+  }
+
+  /// Return the inner writer and the hashes of the data written.
+  pub fn finish(self) -> (W, Hashes)
+  {
+    (self.inner, self.hasher.finalize())
+  }
+}
+
+impl<W : AsyncWrite + Unpin> AsyncWrite for HashingWriter<W>
+{
+  fn poll_write(mut self : Pin<&mut Self>, cx : &mut Context<'_>, buf : &[u8]) -> Poll<io::Result<usize>>
+  {
+    let this = &mut *self;
+    match Pin::new(&mut this.inner).poll_write(cx, buf)
+    {
+      Poll::Ready(Ok(written)) =>
+      {
+        this.hasher.update(&buf[..written]);
+        Poll::Ready(Ok(written))
+      },
+      poll => poll,
+    }
+  }
+
+  fn poll_flush(mut self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<io::Result<()>>
+  {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<io::Result<()>>
+  {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
+
+/// Return the hashes of a file.
+pub fn hash_file(path : &Path) -> io::Result<Hashes>
+{
+  let mut file = fs::File::open(path)?;
+  let mut hasher = Hasher::default();
+  let mut buffer = vec![0u8; 1024*1024];
+
+  loop
+  {
+    match file.read(&mut buffer)
+    {
+      Ok(0) => break,
+      Ok(readed) => hasher.update(&buffer[..readed]),
+      Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      Err(err) => return Err(err),
+    }
+  }
+
+  Ok(hasher.finalize())
+}
+
+/// Manifest of an uploaded file, returned to the client and saved next to the file.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UploadInfo
+{
+  /// Absolute path of the stored file, that can be passed to the local plugin.
+  pub path : PathBuf,
+  pub size : u64,
+  pub hashes : Hashes,
+  /// Name of the user that uploaded the file.
+  pub user : String,
+  /// Time at which the upload was completed.
+  pub received : DateTime<Utc>,
+}
+
+impl UploadInfo
+{
+  pub fn manifest_path(&self) -> PathBuf
+  {
+    let mut manifest_path = self.path.clone().into_os_string();
+    manifest_path.push(MANIFEST_SUFFIX);
+    manifest_path.into()
+  }
+
+  /// Write the manifest next to the uploaded file.
+  pub fn save(&self) -> io::Result<()>
+  {
+    let manifest = serde_json::to_vec_pretty(self).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    fs::write(self.manifest_path(), manifest)
+  }
+}
+
+/// Return the manifests of all the files in the upload directory.
+pub fn list_uploads(upload_dir : &Path) -> io::Result<Vec<UploadInfo>>
+{
+  let mut uploads = Vec::new();
+  let mut dirs = vec![upload_dir.to_path_buf()];
+
+  while let Some(dir) = dirs.pop()
+  {
+    for entry in fs::read_dir(&dir)?
+    {
+      let entry = entry?;
+      let path = entry.path();
+      if entry.file_type()?.is_dir()
+      {
+        if entry.file_name() != SESSIONS_DIR
+        {
+          dirs.push(path);
+        }
+        continue
+      }
+
+      if path.to_string_lossy().ends_with(MANIFEST_SUFFIX)
+      {
+        match fs::read(&path).map_err(|err| err.to_string()).and_then(|data| serde_json::from_slice::<UploadInfo>(&data).map_err(|err| err.to_string()))
+        {
+          Ok(upload) => uploads.push(upload),
+          Err(err) => warn!("Can't read manifest {} : {}", path.display(), err),
+        }
+      }
+    }
+  }
+
+  uploads.sort_by(|a, b| a.received.cmp(&b.received));
+  Ok(uploads)
+}
+
+/// Convert a client supplied name to a path relative to the upload directory,
+/// absolute path, parent directory, the sessions directory and manifest names are rejected.
+pub fn sanitize_name(name : &str) -> io::Result<PathBuf>
+{
+  let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid file name {}", name));
+
+  let name = name.replace('\\', "/");
+  let mut path = PathBuf::new();
+  for component in Path::new(&name).components()
+  {
+    match component
+    {
+      Component::Normal(part) => path.push(part),
+      Component::CurDir => (),
+      _ => return Err(invalid()),
+    }
+  }
+
+  match path.as_os_str().is_empty() || path.starts_with(SESSIONS_DIR) || name.ends_with(MANIFEST_SUFFIX)
+  {
+    true => Err(invalid()),
+    false => Ok(path),
+  }
+}
+
+/// Return `path` with `-index` added before the extension.
+fn indexed_path(path : &Path, index : u32) -> PathBuf
+{
+  let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+  let name = match path.extension()
+  {
+    Some(extension) => format!("{}-{}.{}", stem, index, extension.to_string_lossy()),
+    None => format!("{}-{}", stem, index),
+  };
+  path.with_file_name(name)
+}
+
+/// Create the directories of `relative` inside `upload_dir` one by one without following symlinks,
+/// as a symlink in the upload directory could point outside of it.
+fn create_parents(upload_dir : &Path, relative : &Path, name : &str) -> io::Result<()>
+{
+  let mut dir = upload_dir.to_path_buf();
+  for component in relative.parent().into_iter().flat_map(Path::components)
+  {
+    dir.push(component);
+    match fs::symlink_metadata(&dir)
+    {
+      Ok(metadata) if metadata.is_dir() => (),
+      Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid file name {}, {} is not a directory", name, dir.display()))),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => match fs::create_dir(&dir)
+      {
+        //created by another upload since we checked
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists && fs::symlink_metadata(&dir)?.is_dir() => (),
+        result => result?,
+      },
+      Err(err) => return Err(err),
+    }
+  }
+  Ok(())
+}
+
+/// Reserve the path where an upload named `name` will be written inside `upload_dir`,
+/// the file is created empty unless it's overwritten. Return the absolute path of the file.
+pub fn reserve_path(upload_dir : &Path, name : &str, conflict : Conflict) -> io::Result<PathBuf>
+{
+  let upload_dir = fs::canonicalize(upload_dir)?;
+  let relative = sanitize_name(name)?;
+  create_parents(&upload_dir, &relative, name)?;
+  let path = upload_dir.join(relative);
+
+  //create_new never follow a symlink, but an overwritten file must not be one
+  let create_new = |path : &Path| OpenOptions::new().write(true).create_new(true).open(path).map(|_| ());
+
+  match conflict
+  {
+    Conflict::Overwrite => match fs::symlink_metadata(&path)
+    {
+      Ok(metadata) if !metadata.is_file() => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't overwrite {}, it's not a regular file", name))),
+      Ok(_) => Ok(path),
+      Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(path),
+      Err(err) => Err(err),
+    },
+    Conflict::Reject => match create_new(&path)
+    {
+      Ok(()) => Ok(path),
+      Err(err) if err.kind() == io::ErrorKind::AlreadyExists =>
+        Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("File {} already exists", name))),
+      Err(err) => Err(err),
+    },
+    Conflict::Rename =>
+    {
+      let mut candidate = path.clone();
+      let mut index = 0;
+      loop
+      {
+        match create_new(&candidate)
+        {
+          Ok(()) => return Ok(candidate),
+          Err(err) if err.kind() == io::ErrorKind::AlreadyExists =>
+          {
+            index += 1;
+            candidate = indexed_path(&path, index);
+          },
+          Err(err) => return Err(err),
+        }
+      }
+    },
+  }
+}
+
+/// Check that an upload named `name` can be stored in `upload_dir` with `conflict`,
+/// to refuse it before receiving the data. The check is done again when the file is moved to it's place.
+pub fn check_conflict(upload_dir : &Path, name : &str, conflict : Conflict) -> io::Result<()>
+{
+  match (conflict, fs::symlink_metadata(upload_dir.join(sanitize_name(name)?)))
+  {
+    (Conflict::Reject, Ok(_)) => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("File {} already exists", name))),
+    (Conflict::Overwrite, Ok(metadata)) if !metadata.is_file() => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't overwrite {}, it's not a regular file", name))),
+    _ => Ok(()),
+  }
+}
+
+/// Store the uploaded `data` in `upload_dir` and hash it while it's written,
+/// then check the expected hashes and save the manifest.
+/// The data is written to a temporary file in the sessions directory and only moved to it's place once it's verified,
+/// so an existing file is never lost and incomplete files can't be loaded.
+pub async fn store(upload_dir : &Path, name : &str, conflict : Conflict, expected : &ExpectedHashes, user : &str, data : Data<'_>) -> io::Result<UploadInfo>
+{
+  let upload_dir = fs::canonicalize(upload_dir)?;
+  //fail early instead of after receiving the whole file
+  check_conflict(&upload_dir, name, conflict)?;
+
+  let temp_path = upload_dir.join(SESSIONS_DIR).join(Uuid::new_v4().to_string() + ".upload");
+  let mut writer = HashingWriter::new(File::create(&temp_path).await?);
+
+  let written = match data.open(4096.gibibytes()).stream_to(&mut writer).await
+  {
+    Ok(n) if n.complete => Ok(n.written),
+    Ok(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "File uploaded is not complete")),
+    Err(err) => Err(err),
+  };
+  let (file, hashes) = writer.finish();
+
+  let result = match written
+  {
+    Ok(size) => match file.sync_all().await
+    {
+      Ok(()) =>
+      {
+        drop(file);
+        move_upload(&upload_dir, &temp_path, name, conflict, expected, UploadInfo{ path : PathBuf::new(), size, hashes, user : user.into(), received : Utc::now() })
+      },
+      Err(err) => Err(err),
+    },
+    Err(err) => Err(err),
+  };
+
+  if result.is_err()
+  {
+    let _ = async_fs::remove_file(&temp_path).await;
+  }
+  result
+}
+
+/// Check the hashes of a received file, then move it from `temp_path` to it's place and save it's manifest.
+pub fn move_upload(upload_dir : &Path, temp_path : &Path, name : &str, conflict : Conflict, expected : &ExpectedHashes, mut info : UploadInfo) -> io::Result<UploadInfo>
+{
+  expected.verify(&info.hashes)?;
+  info.path = reserve_path(upload_dir, name, conflict)?;
+  //rename replace a symlink instead of following it
+  if let Err(err) = fs::rename(temp_path, &info.path)
+  {
+    //remove the empty file created by reserve_path
+    if conflict != Conflict::Overwrite
+    {
+      let _ = fs::remove_file(&info.path);
+    }
+    return Err(err)
+  }
+  info.save()?;
+  Ok(info)
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  #[test]
+  fn sanitize_name_keeps_relative_paths()
+  {
+    assert_eq!(sanitize_name("image.dd").unwrap(), PathBuf::from("image.dd"));
+    assert_eq!(sanitize_name("case/disk/image.dd").unwrap(), PathBuf::from("case/disk/image.dd"));
+    assert_eq!(sanitize_name("./case/./image.dd").unwrap(), PathBuf::from("case/image.dd"));
+    assert_eq!(sanitize_name("case\\image.dd").unwrap(), PathBuf::from("case/image.dd"));
+  }
+
+  #[test]
+  fn sanitize_name_rejects_escaping_paths()
+  {
+    for name in ["", ".", "/etc/passwd", "../image.dd", "case/../../image.dd", "..\\image.dd", "\\image.dd"]
+    {
+      assert_eq!(sanitize_name(name).unwrap_err().kind(), io::ErrorKind::InvalidInput, "{}", name);
+    }
+  }
+
+  #[test]
+  fn sanitize_name_rejects_sessions_dir()
+  {
+    assert!(sanitize_name(&format!("{}/session", SESSIONS_DIR)).is_err());
+    assert!(sanitize_name(&format!("./{}", SESSIONS_DIR)).is_err());
+  }
+
+  #[test]
+  fn sanitize_name_rejects_manifests()
+  {
+    assert!(sanitize_name(&format!("image.dd{}", MANIFEST_SUFFIX)).is_err());
+    assert!(sanitize_name(&format!("case/image.dd{}", MANIFEST_SUFFIX)).is_err());
+  }
+}
@@ -0,0 +1,381 @@
+//! Resumable uploads, a file is sent in chunks that can be written at any offset
+//! and the upload can be resumed after an interruption.
+
+use std::fs;
+use std::sync::{Mutex, MutexGuard};
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
+use log::warn;
+use uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use rocket::http::Status;
+use rocket::data::{Data, ToByteUnit};
+use rocket::tokio::fs::OpenOptions;
+use rocket::tokio::io::AsyncSeekExt;
+
+use crate::upload::{self, Conflict, ExpectedHashes, UploadInfo, SESSIONS_DIR};
+use crate::error::ApiError;
+
+/// Parameters sent by the client to create an upload session.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct NewUploadSession
+{
+  pub name : String,
+  pub size : u64,
+  /// Hashes of the file, checked when the upload is finalized.
+  #[serde(flatten)]
+  pub expected : ExpectedHashes,
+  pub conflict : Option<Conflict>,
+}
+
+/// State of an upload session, saved next to the data so it survive a server restart.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct UploadSession
+{
+  pub id : String,
+  pub name : String,
+  pub size : u64,
+  #[serde(flatten)]
+  pub expected : ExpectedHashes,
+  pub conflict : Conflict,
+  pub user : String,
+  pub created : DateTime<Utc>,
+  /// Sorted and merged [start, end) ranges received so far.
+  pub received : Vec<(u64, u64)>,
+}
+
+impl UploadSession
+{
+  fn add_range(&mut self, start : u64, end : u64)
+  {
+    if start == end
+    {
+      return
+    }
+
+    self.received.push((start, end));
+    self.received.sort_unstable();
+
+    let mut merged : Vec<(u64, u64)> = Vec::with_capacity(self.received.len());
+    for (start, end) in self.received.drain(..)
+    {
+      match merged.last_mut()
+      {
+        Some(last) if start <= last.1 => last.1 = last.1.max(end),
+        _ => merged.push((start, end)),
+      }
+    }
+    self.received = merged;
+  }
+
+  pub fn is_complete(&self) -> bool
+  {
+    self.size == 0 || self.received == [(0, self.size)]
+  }
+}
+
+/// A session and the operations in progress on it's data.
+struct SessionEntry
+{
+  session : UploadSession,
+  /// Number of chunks being written.
+  writers : usize,
+  /// The data is being hashed and moved, no chunk can be written.
+  finalizing : bool,
+}
+
+/// Upload sessions in progress, the data and the state of each session are kept in the upload directory.
+pub struct UploadSessions
+{
+  dir : PathBuf,
+  sessions : Mutex<HashMap<String, SessionEntry>>,
+}
+
+/// Chunk being written to a session, the session can't be finalized until it's dropped.
+struct ChunkWriter<'a>
+{
+  sessions : &'a UploadSessions,
+  id : &'a str,
+}
+
+impl Drop for ChunkWriter<'_>
+{
+  fn drop(&mut self)
+  {
+    if let Some(entry) = self.sessions.lock().get_mut(self.id)
+    {
+      entry.writers -= 1;
+    }
+  }
+}
+
+impl UploadSessions
+{
+  /// Open the sessions directory in `upload_dir` and reload sessions left by a previous run.
+  pub fn open(upload_dir : &Path) -> io::Result<Self>
+  {
+    let dir = upload_dir.join(SESSIONS_DIR);
+    fs::create_dir_all(&dir)?;
+
+    let mut sessions = HashMap::new();
+    for entry in fs::read_dir(&dir)?
+    {
+      let path = entry?.path();
+      if path.extension().map_or(true, |extension| extension != "json")
+      {
+        continue
+      }
+
+      match fs::read(&path).map_err(|err| err.to_string()).and_then(|data| serde_json::from_slice::<UploadSession>(&data).map_err(|err| err.to_string()))
+      {
+        Ok(session) => { sessions.insert(session.id.clone(), SessionEntry{ session, writers : 0, finalizing : false }); },
+        Err(err) => warn!("Can't load upload session {} : {}", path.display(), err),
+      }
+    }
+
+    Ok(UploadSessions{ dir, sessions : Mutex::new(sessions) })
+  }
+
+  /// Directory where the data of the sessions is written.
+  pub fn dir(&self) -> &Path
+  {
+    &self.dir
+  }
+
+  fn lock(&self) -> MutexGuard<'_, HashMap<String, SessionEntry>>
+  {
+    self.sessions.lock().unwrap()
+  }
+
+  fn data_path(&self, id : &str) -> PathBuf
+  {
+    self.dir.join(id.to_owned() + ".part")
+  }
+
+  fn state_path(&self, id : &str) -> PathBuf
+  {
+    self.dir.join(id.to_owned() + ".json")
+  }
+
+  fn save_state(&self, session : &UploadSession) -> io::Result<()>
+  {
+    let state = serde_json::to_vec(session).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    fs::write(self.state_path(&session.id), state)
+  }
+
+  fn not_found(id : &str) -> io::Error
+  {
+    io::Error::new(io::ErrorKind::NotFound, format!("Upload session {} not found", id))
+  }
+
+  fn busy(id : &str, reason : &str) -> ApiError
+  {
+    ApiError::new(Status::Conflict, "session_busy", format!("Upload session {} {}", id, reason))
+  }
+
+  /// Create a new session and allocate the file that will receive the data,
+  /// fail if the file can't be stored in `upload_dir` because of a name conflict.
+  /// Must be called from a blocking thread as the file is allocated.
+  pub fn create(&self, user : &str, new_session : NewUploadSession, upload_dir : &Path) -> io::Result<UploadSession>
+  {
+    if new_session.expected.is_empty()
+    {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "At least one of md5, sha1 or sha256 must be provided"))
+    }
+    let conflict = new_session.conflict.unwrap_or_default();
+    upload::check_conflict(upload_dir, &new_session.name, conflict)?;
+
+    let session = UploadSession{ id : Uuid::new_v4().to_string(),
+                                 name : new_session.name,
+                                 size : new_session.size,
+                                 expected : new_session.expected,
+                                 conflict,
+                                 user : user.into(),
+                                 created : Utc::now(),
+                                 received : Vec::new() };
+
+    let file = fs::File::create(self.data_path(&session.id))?;
+    file.set_len(session.size)?;
+    self.save_state(&session)?;
+
+    self.lock().insert(session.id.clone(), SessionEntry{ session : session.clone(), writers : 0, finalizing : false });
+    Ok(session)
+  }
+
+  pub fn get(&self, id : &str) -> io::Result<UploadSession>
+  {
+    self.lock().get(id).map(|entry| entry.session.clone()).ok_or_else(|| Self::not_found(id))
+  }
+
+  pub fn list(&self) -> Vec<UploadSession>
+  {
+    self.lock().values().map(|entry| entry.session.clone()).collect()
+  }
+
+  /// Mark a chunk as being written, fail if the session is being finalized.
+  fn start_writing<'a>(&'a self, id : &'a str) -> Result<(ChunkWriter<'a>, u64), ApiError>
+  {
+    let mut sessions = self.lock();
+    let entry = sessions.get_mut(id).ok_or_else(|| Self::not_found(id))?;
+    if entry.finalizing
+    {
+      return Err(Self::busy(id, "is being finalized"))
+    }
+    entry.writers += 1;
+    Ok((ChunkWriter{ sessions : self, id }, entry.session.size))
+  }
+
+  /// Write a chunk of data at `offset` and return the updated session.
+  pub async fn write_chunk(&self, id : &str, offset : u64, data : Data<'_>) -> Result<UploadSession, ApiError>
+  {
+    let (_writer, size) = self.start_writing(id)?;
+    if offset > size
+    {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "Offset is after the end of the file").into())
+    }
+
+    let mut file = OpenOptions::new().write(true).open(self.data_path(id)).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let n = data.open((size - offset).bytes()).stream_to(&mut file).await?;
+    file.sync_data().await?;
+
+    let mut sessions = self.lock();
+    let session = &mut sessions.get_mut(id).ok_or_else(|| Self::not_found(id))?.session;
+    session.add_range(offset, offset + n.written);
+    self.save_state(session)?;
+
+    match n.complete
+    {
+      true => Ok(session.clone()),
+      false => Err(io::Error::new(io::ErrorKind::InvalidInput, "Chunk is larger than the remaining file size").into()),
+    }
+  }
+
+  /// Check that all the data was received and match the expected hashes,
+  /// then move the file to it's final place in `upload_dir` and save it's manifest.
+  /// Fail if a chunk is still being written. Must be called from a blocking thread as it read the whole file.
+  pub fn finalize(&self, id : &str, upload_dir : &Path) -> Result<UploadInfo, ApiError>
+  {
+    let session = 
+    {
+      let mut sessions = self.lock();
+      let entry = sessions.get_mut(id).ok_or_else(|| Self::not_found(id))?;
+      if entry.finalizing || entry.writers > 0
+      {
+        return Err(Self::busy(id, "has chunks being written or is already being finalized"))
+      }
+      if !entry.session.is_complete()
+      {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Upload is not complete").into())
+      }
+      entry.finalizing = true;
+      entry.session.clone()
+    };
+
+    let result = self.move_data(&session, upload_dir).map_err(ApiError::from);
+    match result
+    {
+      Ok(_) =>
+      {
+        self.lock().remove(id);
+        fs::remove_file(self.state_path(id))?;
+      },
+      Err(_) => if let Some(entry) = self.lock().get_mut(id)
+      {
+        entry.finalizing = false;
+      },
+    }
+    result
+  }
+
+  fn move_data(&self, session : &UploadSession, upload_dir : &Path) -> io::Result<UploadInfo>
+  {
+    let data_path = self.data_path(&session.id);
+    let hashes = upload::hash_file(&data_path)?;
+
+    let info = UploadInfo{ path : PathBuf::new(), size : session.size, hashes, user : session.user.clone(), received : Utc::now() };
+    upload::move_upload(upload_dir, &data_path, &session.name, session.conflict, &session.expected, info)
+  }
+
+  /// Remove a session and the data received so far.
+  pub fn remove(&self, id : &str) -> Result<(), ApiError>
+  {
+    {
+      let mut sessions = self.lock();
+      match sessions.get(id)
+      {
+        None => return Err(Self::not_found(id)),
+        Some(entry) if entry.writers > 0 || entry.finalizing => return Err(Self::busy(id, "has chunks being written or is being finalized")),
+        Some(_) => sessions.remove(id),
+      };
+    }
+
+    let data_path = self.data_path(id);
+    if data_path.exists()
+    {
+      fs::remove_file(data_path)?;
+    }
+    Ok(fs::remove_file(self.state_path(id))?)
+  }
+}
+
+#[cfg(test)]
+mod tests
+{
+  use super::*;
+
+  fn session(size : u64) -> UploadSession
+  {
+    UploadSession{ id : "id".into(), name : "disk.raw".into(), size, expected : ExpectedHashes::default(), conflict : Conflict::Reject,
+                   user : "user".into(), created : Utc::now(), received : Vec::new() }
+  }
+
+  fn received(size : u64, ranges : &[(u64, u64)]) -> UploadSession
+  {
+    let mut session = session(size);
+    for (start, end) in ranges
+    {
+      session.add_range(*start, *end);
+    }
+    session
+  }
+
+  #[test]
+  fn empty_ranges_are_ignored()
+  {
+    assert!(received(10, &[(4, 4)]).received.is_empty());
+  }
+
+  #[test]
+  fn disjoint_ranges_are_sorted()
+  {
+    assert_eq!(received(10, &[(6, 8), (0, 2)]).received, vec![(0, 2), (6, 8)]);
+  }
+
+  #[test]
+  fn adjacent_and_overlapping_ranges_are_merged()
+  {
+    assert_eq!(received(10, &[(0, 4), (4, 6)]).received, vec![(0, 6)]);
+    assert_eq!(received(10, &[(2, 6), (0, 3), (5, 8)]).received, vec![(0, 8)]);
+    assert_eq!(received(10, &[(0, 8), (2, 4)]).received, vec![(0, 8)]);
+  }
+
+  #[test]
+  fn a_range_can_fill_the_gap_between_two_ranges()
+  {
+    assert_eq!(received(10, &[(0, 2), (6, 10), (2, 6)]).received, vec![(0, 10)]);
+  }
+
+  #[test]
+  fn session_is_complete_when_every_byte_is_received()
+  {
+    assert!(!received(10, &[(0, 4), (6, 10)]).is_complete());
+    assert!(received(10, &[(6, 10), (0, 4), (4, 6)]).is_complete());
+    assert!(received(10, &[(0, 10), (0, 10)]).is_complete());
+    assert!(session(0).is_complete());
+  }
+}